@@ -1,8 +1,19 @@
-use std::{io::Write, net::TcpStream};
+mod report;
 
-use clap::{Parser, Subcommand};
+use std::{
+    io::{Read, Write},
+    net::{Shutdown, TcpStream},
+};
 
-use shared::Command;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use shared::{
+    AuditOptions, AuditRule, Command, CrawlOptions, ExternalLinkOptions, FetchOptions, HostScope,
+    LinkKind, LinkOptions, RecrawlPolicy, Response, RobotsOptions, ScopePolicy, UrlFilters,
+    UrlPattern,
+};
+
+use report::{format_audit_findings, format_broken_links, ReportFormat};
 
 #[derive(Parser)]
 #[command(
@@ -18,8 +29,59 @@ struct Cli {
 enum CliCommand {
     /// Starts crawling a given URL
     Start {
-        /// The URL to start crawling
+        /// The URL to start crawling, e.g. `www.example.com` or `https://example.com/docs/`
         url: String,
+        /// Which hosts count as part of the crawled site
+        #[arg(long, value_enum, default_value_t = ScopeArg::Exact)]
+        scope: ScopeArg,
+        /// Additional host to crawl when using `--scope hosts` (may be repeated)
+        #[arg(long = "allow-host", required_if_eq("scope", "hosts"))]
+        allow_hosts: Vec<String>,
+        /// Treat `http` and `https` links as the same site
+        #[arg(long)]
+        any_scheme: bool,
+        /// Only follow links below the directory of the start URL
+        #[arg(long)]
+        path_scope: bool,
+        /// Only crawl links matching this glob, matched against the link as written (may be repeated)
+        #[arg(long)]
+        include: Vec<String>,
+        /// Never crawl links matching this glob, e.g. `/logout` or `*.pdf`, matched against the link
+        /// as written (may be repeated)
+        #[arg(long)]
+        exclude: Vec<String>,
+        /// Only crawl links matching this regex, matched against the link as written (may be repeated)
+        #[arg(long)]
+        include_regex: Vec<String>,
+        /// Never crawl links matching this regex, matched against the link as written (may be repeated)
+        #[arg(long)]
+        exclude_regex: Vec<String>,
+        /// Maximum number of bytes downloaded for each page (defaults to 10 MiB)
+        #[arg(long)]
+        max_body_size: Option<u64>,
+        /// Send a HEAD request first for links that look like downloads (`.pdf`, `.zip`, ...)
+        #[arg(long)]
+        head_check: bool,
+        /// Elements to extract links from. Links from `a`, `area`, `iframe`, `frame` and
+        /// `meta-refresh` are followed, the rest are only recorded
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "a")]
+        links: Vec<LinkElementArg>,
+        /// Follow links marked `rel="nofollow"`
+        #[arg(long)]
+        ignore_rel_nofollow: bool,
+        /// Follow links on pages with a `nofollow` robots meta tag
+        #[arg(long)]
+        ignore_meta_nofollow: bool,
+        /// Don't mark pages with a `noindex` robots meta tag
+        #[arg(long)]
+        ignore_meta_noindex: bool,
+        /// Ignore the `X-Robots-Tag` response header
+        #[arg(long)]
+        ignore_x_robots_tag: bool,
+        #[command(flatten)]
+        recrawl: Box<RecrawlArgs>,
+        #[command(flatten)]
+        external: Box<ExternalArgs>,
     },
     /// Stops crawling a given URL
     Stop {
@@ -28,18 +90,315 @@ enum CliCommand {
     },
     /// Lists all crawled URLs
     List,
+    /// Shows which pages were added, removed, changed or broken between two runs of a crawl
+    Diff {
+        /// The crawled site, as given to `start`
+        site: String,
+        /// The earlier run to compare (defaults to the one before the later run)
+        run_a: Option<u32>,
+        /// The later run to compare (defaults to the latest run)
+        run_b: Option<u32>,
+    },
+    /// Lists the URLs of a crawl that are broken, with the pages linking to them. Exits with
+    /// status 1 if there are any
+    Broken {
+        /// The crawled site, as given to `start`
+        site: String,
+        #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+        format: ReportFormat,
+    },
+    /// Shows what was found about a crawled URL: its title, description, canonical URL,
+    /// headings, language, word count, Open Graph tags and JSON-LD
+    Show {
+        /// The crawled URL
+        url: String,
+    },
+    /// Checks the pages of a crawl's latest run for SEO problems: missing or duplicate titles
+    /// and descriptions, missing or multiple `<h1>`s, canonicals pointing elsewhere, redirect
+    /// chains, sitemap URLs nothing links to, pages too many clicks deep, noindex pages in the
+    /// navigation and images without `alt`. Exits with status 1 if anything is found
+    Audit {
+        /// The crawled site, as given to `start`
+        site: String,
+        /// Rules to leave out, e.g. `image-without-alt` (may be repeated, or comma separated)
+        #[arg(long, value_enum, value_delimiter = ',')]
+        skip: Vec<AuditRuleArg>,
+        /// Report chains of more redirects than this (defaults to 1)
+        #[arg(long)]
+        max_redirects: Option<u32>,
+        /// Report pages more clicks than this away from the start URL (defaults to 3)
+        #[arg(long)]
+        max_depth: Option<u32>,
+        #[arg(long, value_enum, default_value_t = ReportFormat::Table)]
+        format: ReportFormat,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ScopeArg {
+    /// Only the host of the start URL
+    Exact,
+    /// The host of the start URL and its subdomains
+    Subdomains,
+    /// Any host sharing the start URL's registrable domain
+    Domain,
+    /// The host of the start URL plus the hosts given with `--allow-host`
+    Hosts,
+}
+
+#[derive(Args)]
+struct RecrawlArgs {
+    /// When pages that were already fetched are fetched again
+    #[arg(long, value_enum, default_value_t = RecrawlArg::Never)]
+    recrawl: RecrawlArg,
+    /// How long to wait between fetches with `--recrawl interval`, e.g. `90s`, `30m`, `6h` or
+    /// `1d`
+    #[arg(long, value_parser = parse_duration, required_if_eq("recrawl", "interval"))]
+    recrawl_every: Option<u64>,
+    /// When to fetch pages with `--recrawl cron`, e.g. `0 3 * * *` for 3am every day
+    #[arg(long, required_if_eq("recrawl", "cron"))]
+    recrawl_cron: Option<String>,
+    /// Shortest wait between fetches with `--recrawl adaptive` (defaults to 1h)
+    #[arg(long, value_parser = parse_duration)]
+    recrawl_min: Option<u64>,
+    /// Longest wait between fetches with `--recrawl adaptive` (defaults to 7d)
+    #[arg(long, value_parser = parse_duration)]
+    recrawl_max: Option<u64>,
+}
+
+impl RecrawlArgs {
+    fn policy(&self) -> RecrawlPolicy {
+        match self.recrawl {
+            RecrawlArg::Never => RecrawlPolicy::Never,
+            // clap requires the value each policy needs
+            RecrawlArg::Interval => RecrawlPolicy::Interval(self.recrawl_every.unwrap()),
+            RecrawlArg::Cron => RecrawlPolicy::Cron(self.recrawl_cron.clone().unwrap()),
+            RecrawlArg::Adaptive => RecrawlPolicy::Adaptive {
+                min_secs: self.recrawl_min.unwrap_or(60 * 60),
+                max_secs: self.recrawl_max.unwrap_or(7 * 24 * 60 * 60),
+            },
+        }
+    }
+}
+
+#[derive(Args)]
+struct ExternalArgs {
+    /// Check that links to other sites work, with a HEAD request (or a GET where HEAD isn't
+    /// allowed). They're listed but never followed
+    #[arg(long)]
+    check_external: bool,
+    /// How long each external check may take, e.g. `10s` (the default)
+    #[arg(long, value_parser = parse_duration)]
+    external_timeout: Option<u64>,
+    /// The wait between checks of links to the same host, e.g. `1s` (the default)
+    #[arg(long, value_parser = parse_duration)]
+    external_delay: Option<u64>,
+}
+
+impl ExternalArgs {
+    fn options(&self) -> ExternalLinkOptions {
+        let default = ExternalLinkOptions::default();
+        ExternalLinkOptions {
+            check: self.check_external,
+            timeout_secs: self.external_timeout.unwrap_or(default.timeout_secs),
+            host_delay_secs: self.external_delay.unwrap_or(default.host_delay_secs),
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum RecrawlArg {
+    /// Each page is fetched once per `start`
+    Never,
+    /// Every `--recrawl-every`
+    Interval,
+    /// On the `--recrawl-cron` schedule
+    Cron,
+    /// Sooner for pages that change often, later for ones that don't
+    Adaptive,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LinkElementArg {
+    A,
+    Area,
+    Link,
+    Iframe,
+    Frame,
+    Img,
+    Script,
+    Source,
+    Form,
+    MetaRefresh,
+    Object,
+}
+
+impl LinkElementArg {
+    fn link_kinds(&self) -> &'static [LinkKind] {
+        match self {
+            LinkElementArg::A => &[LinkKind::AnchorHref],
+            LinkElementArg::Area => &[LinkKind::AreaHref],
+            LinkElementArg::Link => &[LinkKind::LinkHref],
+            LinkElementArg::Iframe => &[LinkKind::IframeSrc],
+            LinkElementArg::Frame => &[LinkKind::FrameSrc],
+            LinkElementArg::Img => &[LinkKind::ImgSrc, LinkKind::ImgSrcset],
+            LinkElementArg::Script => &[LinkKind::ScriptSrc],
+            LinkElementArg::Source => &[LinkKind::SourceSrc, LinkKind::SourceSrcset],
+            LinkElementArg::Form => &[LinkKind::FormAction],
+            LinkElementArg::MetaRefresh => &[LinkKind::MetaRefresh],
+            LinkElementArg::Object => &[LinkKind::ObjectData],
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum AuditRuleArg {
+    MissingTitle,
+    DuplicateTitle,
+    MissingDescription,
+    DuplicateDescription,
+    MissingH1,
+    MultipleH1,
+    /// The canonical URL is another page
+    NonSelfCanonical,
+    /// More redirects in a row than `--max-redirects`
+    RedirectChain,
+    /// Listed in a sitemap, but not linked from any crawled page
+    OrphanedSitemapUrl,
+    /// More clicks from the start URL than `--max-depth`
+    TooDeep,
+    /// A noindex page linked from a `<nav>`
+    NoindexInNavigation,
+    ImageWithoutAlt,
+}
+
+impl AuditRuleArg {
+    fn rule(&self) -> AuditRule {
+        match self {
+            AuditRuleArg::MissingTitle => AuditRule::MissingTitle,
+            AuditRuleArg::DuplicateTitle => AuditRule::DuplicateTitle,
+            AuditRuleArg::MissingDescription => AuditRule::MissingDescription,
+            AuditRuleArg::DuplicateDescription => AuditRule::DuplicateDescription,
+            AuditRuleArg::MissingH1 => AuditRule::MissingH1,
+            AuditRuleArg::MultipleH1 => AuditRule::MultipleH1,
+            AuditRuleArg::NonSelfCanonical => AuditRule::NonSelfCanonical,
+            AuditRuleArg::RedirectChain => AuditRule::RedirectChain,
+            AuditRuleArg::OrphanedSitemapUrl => AuditRule::OrphanedSitemapUrl,
+            AuditRuleArg::TooDeep => AuditRule::TooDeep,
+            AuditRuleArg::NoindexInNavigation => AuditRule::NoindexInNavigation,
+            AuditRuleArg::ImageWithoutAlt => AuditRule::ImageWithoutAlt,
+        }
+    }
 }
 
 impl CliCommand {
     fn to_protocol(&self) -> Command {
         match self {
-            CliCommand::Start { url } => Command::Start(url.clone()),
+            CliCommand::Start {
+                url,
+                scope,
+                allow_hosts,
+                any_scheme,
+                path_scope,
+                include,
+                exclude,
+                include_regex,
+                exclude_regex,
+                max_body_size,
+                head_check,
+                links,
+                ignore_rel_nofollow,
+                ignore_meta_nofollow,
+                ignore_meta_noindex,
+                ignore_x_robots_tag,
+                recrawl,
+                external,
+            } => {
+                let default_fetch = FetchOptions::default();
+                let hosts = match scope {
+                    ScopeArg::Exact => HostScope::ExactHost,
+                    ScopeArg::Subdomains => HostScope::Subdomains,
+                    ScopeArg::Domain => HostScope::RegistrableDomain,
+                    ScopeArg::Hosts => HostScope::AllowedHosts(allow_hosts.clone()),
+                };
+                let options = CrawlOptions {
+                    scope: ScopePolicy {
+                        hosts,
+                        any_scheme: *any_scheme,
+                    },
+                    path_scope: *path_scope,
+                    filters: UrlFilters {
+                        include: to_patterns(include, include_regex),
+                        exclude: to_patterns(exclude, exclude_regex),
+                    },
+                    fetch: FetchOptions {
+                        max_body_bytes: max_body_size.unwrap_or(default_fetch.max_body_bytes),
+                        head_suspicious: *head_check,
+                    },
+                    links: LinkOptions {
+                        extract: links
+                            .iter()
+                            .flat_map(|element| element.link_kinds())
+                            .copied()
+                            .collect(),
+                    },
+                    robots: RobotsOptions {
+                        rel_nofollow: !ignore_rel_nofollow,
+                        meta_nofollow: !ignore_meta_nofollow,
+                        meta_noindex: !ignore_meta_noindex,
+                        x_robots_tag: !ignore_x_robots_tag,
+                    },
+                    recrawl: recrawl.policy(),
+                    external: external.options(),
+                };
+                Command::Start(url.clone(), options)
+            }
             CliCommand::Stop { url } => Command::Stop(url.clone()),
             CliCommand::List => Command::List,
+            CliCommand::Diff { site, run_a, run_b } => Command::Diff(site.clone(), *run_a, *run_b),
+            CliCommand::Broken { site, .. } => Command::Broken(site.clone()),
+            CliCommand::Show { url } => Command::Show(url.clone()),
+            CliCommand::Audit {
+                site,
+                skip,
+                max_redirects,
+                max_depth,
+                ..
+            } => {
+                let default = AuditOptions::default();
+                let options = AuditOptions {
+                    skip: skip.iter().map(AuditRuleArg::rule).collect(),
+                    max_redirects: max_redirects.unwrap_or(default.max_redirects),
+                    max_depth: max_depth.unwrap_or(default.max_depth),
+                };
+                Command::Audit(site.clone(), options)
+            }
         }
     }
 }
 
+fn to_patterns(globs: &[String], regexes: &[String]) -> Vec<UrlPattern> {
+    let globs = globs.iter().cloned().map(UrlPattern::Glob);
+    let regexes = regexes.iter().cloned().map(UrlPattern::Regex);
+    globs.chain(regexes).collect()
+}
+
+// Parses a duration like `90s`, `30m`, `6h` or `1d` into seconds. Plain numbers are seconds.
+fn parse_duration(duration: &str) -> Result<u64, String> {
+    let (number, unit_secs) = match duration.char_indices().last() {
+        Some((index, 's')) => (&duration[..index], 1),
+        Some((index, 'm')) => (&duration[..index], 60),
+        Some((index, 'h')) => (&duration[..index], 60 * 60),
+        Some((index, 'd')) => (&duration[..index], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(unit_secs))
+        .ok_or_else(|| format!("`{}` is not a duration like 90s, 30m, 6h or 1d", duration))
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse CLI arguments
     let cli = Cli::parse();
@@ -49,6 +408,43 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let mut stream = TcpStream::connect("127.0.0.1:8080")?;
     let encoded: Vec<u8> = bincode::serialize(&command)?;
     stream.write_all(&encoded)?;
+    stream.shutdown(Shutdown::Write)?;
+
+    // Wait for the service's response
+    let mut buffer = Vec::new();
+    stream.read_to_end(&mut buffer)?;
+    let response = bincode::deserialize::<Response>(&buffer)?;
+
+    match response {
+        Response::Started(url) => println!("Started crawling {}", url),
+        Response::Stopped(url) => println!("Stopped crawling {}", url),
+        Response::Ok => {}
+        Response::Report(report) => print!("{}", report),
+        Response::BrokenLinks(broken) => {
+            let format = match cli.command {
+                CliCommand::Broken { format, .. } => format,
+                _ => ReportFormat::Table,
+            };
+            print!("{}", format_broken_links(&broken, format));
+            if !broken.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Response::AuditFindings(findings) => {
+            let format = match cli.command {
+                CliCommand::Audit { format, .. } => format,
+                _ => ReportFormat::Table,
+            };
+            print!("{}", format_audit_findings(&findings, format));
+            if !findings.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Response::Error(message) => {
+            eprintln!("Error: {}", message);
+            std::process::exit(1);
+        }
+    }
 
     Ok(())
 }
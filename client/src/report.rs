@@ -0,0 +1,179 @@
+use std::fmt::Write;
+
+use clap::ValueEnum;
+
+use shared::{AuditFinding, BrokenLink};
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ReportFormat {
+    /// Aligned columns, for reading
+    Table,
+    Json,
+    Csv,
+}
+
+const BROKEN_LINK_HEADER: [&str; 4] = ["URL", "STATUS", "LINKED FROM", "LINK TEXT"];
+const AUDIT_HEADER: [&str; 3] = ["RULE", "URL", "DETAIL"];
+
+pub fn format_broken_links(broken: &[BrokenLink], format: ReportFormat) -> String {
+    match format {
+        ReportFormat::Table => format_table(&BROKEN_LINK_HEADER, &broken_link_rows(broken)),
+        ReportFormat::Json => format_json(serde_json::to_string_pretty(broken)),
+        ReportFormat::Csv => format_csv(&BROKEN_LINK_HEADER, &broken_link_rows(broken)),
+    }
+}
+
+pub fn format_audit_findings(findings: &[AuditFinding], format: ReportFormat) -> String {
+    let rows: Vec<[&str; 3]> = findings
+        .iter()
+        .map(|finding| [finding.rule.name(), &finding.url, &finding.detail])
+        .collect();
+    match format {
+        ReportFormat::Table => format_table(&AUDIT_HEADER, &rows),
+        ReportFormat::Json => format_json(serde_json::to_string_pretty(findings)),
+        ReportFormat::Csv => format_csv(&AUDIT_HEADER, &rows),
+    }
+}
+
+fn format_json(json: serde_json::Result<String>) -> String {
+    // the protocol types only hold strings and enums, so this can't fail
+    let mut json = json.unwrap_or_default();
+    json.push('\n');
+    json
+}
+
+// One row per page linking to each broken URL, or a single row if nothing links to it
+fn broken_link_rows(broken: &[BrokenLink]) -> Vec<[&str; 4]> {
+    let mut rows = Vec::new();
+    for broken_link in broken {
+        if broken_link.referrers.is_empty() {
+            rows.push([broken_link.url.as_str(), &broken_link.status, "", ""]);
+        }
+        for referrer in &broken_link.referrers {
+            let text = referrer.text.as_deref().unwrap_or("");
+            rows.push([
+                broken_link.url.as_str(),
+                &broken_link.status,
+                &referrer.page_url,
+                text,
+            ]);
+        }
+    }
+    rows
+}
+
+fn format_table<const N: usize>(header: &[&str; N], rows: &[[&str; N]]) -> String {
+    let mut widths = header.map(|column| column.chars().count());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for row in std::iter::once(header).chain(rows) {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(widths) {
+            let _ = write!(line, "{:width$}  ", cell, width = width);
+        }
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+fn format_csv<const N: usize>(header: &[&str; N], rows: &[[&str; N]]) -> String {
+    let mut csv = String::new();
+    for row in std::iter::once(header).chain(rows) {
+        let cells: Vec<String> = row.iter().map(|cell| csv_field(cell)).collect();
+        csv.push_str(&cells.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+// Quotes the field if it holds a separator, quote or line break, doubling any quotes
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use shared::{AuditRule, Referrer};
+
+    fn broken() -> Vec<BrokenLink> {
+        vec![
+            BrokenLink {
+                url: "https://example.com/gone".to_string(),
+                status: "404 Not Found".to_string(),
+                referrers: vec![
+                    Referrer {
+                        page_url: "https://example.com/".to_string(),
+                        text: Some("Gone, \"really\"".to_string()),
+                    },
+                    Referrer {
+                        page_url: "https://example.com/a".to_string(),
+                        text: None,
+                    },
+                ],
+            },
+            BrokenLink {
+                url: "https://example.com".to_string(),
+                status: "timed out".to_string(),
+                referrers: Vec::new(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_table() {
+        assert_eq!(
+            format_broken_links(&broken(), ReportFormat::Table),
+            "URL                       STATUS         LINKED FROM            LINK TEXT\n\
+             https://example.com/gone  404 Not Found  https://example.com/   Gone, \"really\"\n\
+             https://example.com/gone  404 Not Found  https://example.com/a\n\
+             https://example.com       timed out\n"
+        );
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(
+            format_broken_links(&broken(), ReportFormat::Csv),
+            "URL,STATUS,LINKED FROM,LINK TEXT\r\n\
+             https://example.com/gone,404 Not Found,https://example.com/,\"Gone, \"\"really\"\"\"\r\n\
+             https://example.com/gone,404 Not Found,https://example.com/a,\r\n\
+             https://example.com,timed out,,\r\n"
+        );
+    }
+
+    #[test]
+    fn test_audit_table() {
+        let findings = vec![
+            AuditFinding {
+                rule: AuditRule::MissingH1,
+                url: "https://example.com/a".to_string(),
+                detail: "no <h1>".to_string(),
+            },
+            AuditFinding {
+                rule: AuditRule::TooDeep,
+                url: "https://example.com/a/b/c/d".to_string(),
+                detail: "4 clicks from https://example.com/".to_string(),
+            },
+        ];
+        assert_eq!(
+            format_audit_findings(&findings, ReportFormat::Table),
+            "RULE        URL                          DETAIL\n\
+             missing-h1  https://example.com/a        no <h1>\n\
+             too-deep    https://example.com/a/b/c/d  4 clicks from https://example.com/\n"
+        );
+    }
+}
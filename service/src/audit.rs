@@ -0,0 +1,459 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+
+use url::Url;
+
+use shared::{AuditFinding, AuditOptions, AuditRule};
+
+use crate::{
+    crawler::strip_url_to_domain_and_path,
+    node::Node,
+    parser::PageMetadata,
+    run::{CrawlRun, PageStatus},
+    store::PageLink,
+};
+
+// Checks the pages of a run against the rules that aren't skipped, sorted by rule and URL
+pub(crate) fn audit(
+    run: &CrawlRun,
+    url_nodes: &HashMap<Url, Node>,
+    url_links: &HashMap<Url, Vec<PageLink>>,
+    options: &AuditOptions,
+) -> Vec<AuditFinding> {
+    let mut audit = Audit {
+        run,
+        url_nodes,
+        url_links,
+        options,
+        findings: Vec::new(),
+    };
+    audit.check_text(
+        |metadata| metadata.title.as_deref(),
+        AuditRule::MissingTitle,
+        AuditRule::DuplicateTitle,
+        "title",
+    );
+    audit.check_text(
+        |metadata| metadata.description.as_deref(),
+        AuditRule::MissingDescription,
+        AuditRule::DuplicateDescription,
+        "description",
+    );
+    audit.check_pages();
+    audit.check_redirects();
+    audit.check_links();
+
+    let mut findings = audit.findings;
+    findings.sort_by(|a, b| (a.rule, &a.url, &a.detail).cmp(&(b.rule, &b.url, &b.detail)));
+    findings.dedup_by(|a, b| a.rule == b.rule && a.url == b.url && a.detail == b.detail);
+    findings
+}
+
+struct Audit<'a> {
+    run: &'a CrawlRun,
+    url_nodes: &'a HashMap<Url, Node>,
+    url_links: &'a HashMap<Url, Vec<PageLink>>,
+    options: &'a AuditOptions,
+    findings: Vec<AuditFinding>,
+}
+
+impl Audit<'_> {
+    fn flag(&mut self, rule: AuditRule, url: &Url, detail: String) {
+        if !self.options.skip.contains(&rule) {
+            self.findings.push(AuditFinding {
+                rule,
+                url: url.to_string(),
+                detail,
+            });
+        }
+    }
+
+    // The run's HTML pages that were fetched successfully and read in full, with what they said
+    // about themselves
+    fn html_pages(&self) -> BTreeMap<&Url, &PageMetadata> {
+        self.run
+            .pages
+            .iter()
+            .filter(|(_, page)| matches!(page.status, PageStatus::Http(200..=299)))
+            // what a truncated page is missing may be past where it was cut off
+            .filter(|(_, page)| !page.truncated)
+            .filter_map(|(url, _)| {
+                let node = self.url_nodes.get(url)?;
+                Some((url, node.metadata.as_ref()?))
+            })
+            .collect()
+    }
+
+    // Flags pages without the text, and pages sharing it with others
+    fn check_text(
+        &mut self,
+        text: impl Fn(&PageMetadata) -> Option<&str>,
+        missing: AuditRule,
+        duplicate: AuditRule,
+        name: &str,
+    ) {
+        let mut pages_by_text: BTreeMap<String, Vec<Url>> = BTreeMap::new();
+        let mut pages_without_text = Vec::new();
+        for (url, metadata) in self.html_pages() {
+            match text(metadata).filter(|text| !text.is_empty()) {
+                Some(text) => pages_by_text
+                    .entry(text.to_string())
+                    .or_default()
+                    .push(url.clone()),
+                None => pages_without_text.push(url.clone()),
+            }
+        }
+
+        for url in pages_without_text {
+            self.flag(missing, &url, format!("no {}", name));
+        }
+        for (text, urls) in pages_by_text {
+            if urls.len() < 2 {
+                continue;
+            }
+            for url in &urls {
+                let others = count(urls.len() - 1, "other page");
+                let detail = format!("\"{}\" (shared with {})", text, others);
+                self.flag(duplicate, url, detail);
+            }
+        }
+    }
+
+    // The rules about each page on its own
+    fn check_pages(&mut self) {
+        let pages: Vec<(Url, PageMetadata)> = self
+            .html_pages()
+            .into_iter()
+            .map(|(url, metadata)| (url.clone(), metadata.clone()))
+            .collect();
+        for (url, metadata) in pages {
+            match metadata.h1s.len() {
+                0 => self.flag(AuditRule::MissingH1, &url, "no <h1>".to_string()),
+                1 => {}
+                count => self.flag(AuditRule::MultipleH1, &url, format!("{} <h1>s", count)),
+            }
+
+            // a redirected page's canonical is the URL it ended up at
+            let final_url = self.url_nodes[&url].redirects.last().map_or_else(
+                || url.clone(),
+                |redirect| strip_url_to_domain_and_path(redirect.clone()),
+            );
+            let canonical = metadata.canonical.as_deref().and_then(|canonical| {
+                let canonical_url = Url::parse(canonical).ok()?;
+                (strip_url_to_domain_and_path(canonical_url) != final_url).then_some(canonical)
+            });
+            if let Some(canonical) = canonical {
+                let detail = format!("canonical is {}", canonical);
+                self.flag(AuditRule::NonSelfCanonical, &url, detail);
+            }
+
+            let srcs: BTreeSet<&String> = metadata.images_without_alt.iter().collect();
+            for src in srcs {
+                let detail = format!("<img src=\"{}\"> has no alt", src);
+                self.flag(AuditRule::ImageWithoutAlt, &url, detail);
+            }
+        }
+    }
+
+    fn check_redirects(&mut self) {
+        let mut chains = Vec::new();
+        for url in self.run.pages.keys() {
+            let Some(node) = self.url_nodes.get(url) else {
+                continue;
+            };
+            if node.redirects.len() > self.options.max_redirects as usize {
+                let hops: Vec<&str> = node.redirects.iter().map(Url::as_str).collect();
+                let detail = format!("{}: {}", count(hops.len(), "redirect"), hops.join(" -> "));
+                chains.push((url.clone(), detail));
+            }
+        }
+        for (url, detail) in chains {
+            self.flag(AuditRule::RedirectChain, &url, detail);
+        }
+    }
+
+    // The rules about how pages are linked: from the sitemap, the start URL and the navigation
+    fn check_links(&mut self) {
+        let start_url = strip_url_to_domain_and_path(self.run.start_url.clone());
+        let mut linked_urls = HashSet::new();
+        let mut noindex_nav_links = Vec::new();
+        for page_url in self.run.pages.keys() {
+            for (link_url, edge) in self.links(page_url) {
+                if !edge.kind.is_navigational() {
+                    continue;
+                }
+                let target_url = strip_url_to_domain_and_path(link_url.clone());
+                let is_noindex = self
+                    .url_nodes
+                    .get(&target_url)
+                    .is_some_and(|node| node.noindex);
+                if edge.in_nav && is_noindex {
+                    noindex_nav_links.push((target_url.clone(), page_url.clone()));
+                }
+                linked_urls.insert(target_url);
+            }
+        }
+
+        for (url, page_url) in noindex_nav_links {
+            let detail = format!("in the navigation of {}", page_url);
+            self.flag(AuditRule::NoindexInNavigation, &url, detail);
+        }
+
+        let orphans: Vec<Url> = self
+            .run
+            .sitemap_urls
+            .iter()
+            .filter(|url| **url != start_url && !linked_urls.contains(*url))
+            .cloned()
+            .collect();
+        for url in orphans {
+            let detail = "in a sitemap, but not linked from any crawled page".to_string();
+            self.flag(AuditRule::OrphanedSitemapUrl, &url, detail);
+        }
+
+        let html_pages: Vec<Url> = self.html_pages().into_keys().cloned().collect();
+        let depths = self.click_depths(&start_url);
+        for url in html_pages {
+            let Some(&depth) = depths.get(&url) else {
+                continue;
+            };
+            if depth > self.options.max_depth {
+                let detail = format!(
+                    "{} from {}",
+                    count(depth as usize, "click"),
+                    self.run.start_url
+                );
+                self.flag(AuditRule::TooDeep, &url, detail);
+            }
+        }
+    }
+
+    fn links(&self, page_url: &Url) -> &[PageLink] {
+        self.url_links.get(page_url).map_or(&[], Vec::as_slice)
+    }
+
+    // The fewest clicks it takes to get from the start URL to each page of the run
+    fn click_depths(&self, start_url: &Url) -> HashMap<Url, u32> {
+        let mut depths = HashMap::from([(start_url.clone(), 0)]);
+        let mut queue = VecDeque::from([start_url.clone()]);
+        while let Some(page_url) = queue.pop_front() {
+            let depth = depths[&page_url];
+            for (link_url, edge) in self.links(&page_url) {
+                let target_url = strip_url_to_domain_and_path(link_url.clone());
+                if !edge.kind.is_navigational()
+                    || !self.run.pages.contains_key(&target_url)
+                    || depths.contains_key(&target_url)
+                {
+                    continue;
+                }
+                depths.insert(target_url.clone(), depth + 1);
+                queue.push_back(target_url);
+            }
+        }
+        depths
+    }
+}
+
+// e.g. `1 redirect` or `3 redirects`
+fn count(count: usize, noun: &str) -> String {
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{} {}{}", count, noun, plural)
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use shared::LinkEdge;
+
+    use crate::{
+        run::RunPage,
+        test_util::{anchor_edge, url},
+    };
+
+    // A site of pages linking on to one another in a chain, `/` -> `/a` -> `/b` -> `/c`,
+    // with `/` linking to `/private` from its navigation
+    struct Site {
+        run: CrawlRun,
+        url_nodes: HashMap<Url, Node>,
+        url_links: HashMap<Url, Vec<PageLink>>,
+    }
+
+    impl Site {
+        fn new() -> Self {
+            let mut site = Site {
+                run: CrawlRun {
+                    id: 1,
+                    started_at: chrono::Local::now(),
+                    start_url: url("/"),
+                    pages: HashMap::new(),
+                    sitemap_urls: vec![url("/"), url("/a"), url("/orphan")],
+                },
+                url_nodes: HashMap::new(),
+                url_links: HashMap::new(),
+            };
+            let chain = ["/", "/a", "/b", "/c", "/private"];
+            for (index, path) in chain.into_iter().enumerate() {
+                let metadata = PageMetadata {
+                    title: Some(format!("Page {}", path)),
+                    description: Some(format!("About {}", path)),
+                    h1s: vec![path.to_string()],
+                    ..PageMetadata::default()
+                };
+                site.add_page(path, metadata);
+                if let Some(next_path) = chain.get(index + 1).filter(|_| index < 3) {
+                    site.add_link(path, next_path, false);
+                }
+            }
+            site.add_link("/", "/private", true);
+            site.url_nodes.get_mut(&url("/private")).unwrap().noindex = true;
+            site
+        }
+
+        fn add_page(&mut self, path: &str, metadata: PageMetadata) {
+            let page = RunPage::unparsed(PageStatus::Http(200));
+            self.run.pages.insert(url(path), page);
+            let node = Node {
+                metadata: Some(metadata),
+                ..Node::default()
+            };
+            self.url_nodes.insert(url(path), node);
+        }
+
+        fn add_link(&mut self, from: &str, to: &str, in_nav: bool) {
+            let links = self.url_links.entry(url(from)).or_default();
+            let edge = LinkEdge {
+                in_nav,
+                ..anchor_edge(to)
+            };
+            links.push((url(to), edge));
+        }
+
+        fn metadata(&mut self, path: &str) -> &mut PageMetadata {
+            let node = self.url_nodes.get_mut(&url(path)).unwrap();
+            node.metadata.as_mut().unwrap()
+        }
+
+        fn audit(&self, options: &AuditOptions) -> Vec<(AuditRule, String, String)> {
+            audit(&self.run, &self.url_nodes, &self.url_links, options)
+                .into_iter()
+                .map(|finding| (finding.rule, finding.url, finding.detail))
+                .collect()
+        }
+    }
+
+    fn finding(rule: AuditRule, path: &str, detail: &str) -> (AuditRule, String, String) {
+        (rule, url(path).to_string(), detail.to_string())
+    }
+
+    #[test]
+    fn test_audit() {
+        let mut site = Site::new();
+        site.metadata("/a").title = None;
+        site.metadata("/b").title = Some("Page /c".to_string());
+        site.metadata("/b").description = Some(String::new());
+        site.metadata("/a").h1s.clear();
+        site.metadata("/b").h1s.push("Again".to_string());
+        site.metadata("/c").canonical = Some("https://example.com/b/".to_string());
+        site.metadata("/private").canonical = Some("https://example.com/private/".to_string());
+        site.metadata("/c").images_without_alt = vec!["/x.png".to_string(); 2];
+        let node = site.url_nodes.get_mut(&url("/c")).unwrap();
+        node.redirects = vec![url("/c2"), url("/c3")];
+        site.metadata("/a").canonical = Some("https://example.com/a2".to_string());
+        let node = site.url_nodes.get_mut(&url("/a")).unwrap();
+        node.redirects = vec![url("/a2")];
+
+        assert_eq!(
+            site.audit(&AuditOptions {
+                max_depth: 2,
+                ..AuditOptions::default()
+            }),
+            vec![
+                finding(AuditRule::MissingTitle, "/a", "no title"),
+                finding(
+                    AuditRule::DuplicateTitle,
+                    "/b",
+                    "\"Page /c\" (shared with 1 other page)"
+                ),
+                finding(
+                    AuditRule::DuplicateTitle,
+                    "/c",
+                    "\"Page /c\" (shared with 1 other page)"
+                ),
+                finding(AuditRule::MissingDescription, "/b", "no description"),
+                finding(AuditRule::MissingH1, "/a", "no <h1>"),
+                finding(AuditRule::MultipleH1, "/b", "2 <h1>s"),
+                finding(
+                    AuditRule::NonSelfCanonical,
+                    "/c",
+                    "canonical is https://example.com/b/"
+                ),
+                finding(
+                    AuditRule::RedirectChain,
+                    "/c",
+                    "2 redirects: https://example.com/c2 -> https://example.com/c3"
+                ),
+                finding(
+                    AuditRule::OrphanedSitemapUrl,
+                    "/orphan",
+                    "in a sitemap, but not linked from any crawled page"
+                ),
+                finding(
+                    AuditRule::TooDeep,
+                    "/c",
+                    "3 clicks from https://example.com/"
+                ),
+                finding(
+                    AuditRule::NoindexInNavigation,
+                    "/private",
+                    "in the navigation of https://example.com/"
+                ),
+                finding(
+                    AuditRule::ImageWithoutAlt,
+                    "/c",
+                    "<img src=\"/x.png\"> has no alt"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_skipped_rules() {
+        let mut site = Site::new();
+        site.metadata("/a").title = None;
+        site.metadata("/b").h1s.clear();
+
+        let options = AuditOptions {
+            skip: vec![AuditRule::MissingTitle, AuditRule::NoindexInNavigation],
+            ..AuditOptions::default()
+        };
+        let rules: Vec<AuditRule> = site.audit(&options).into_iter().map(|f| f.0).collect();
+        assert_eq!(
+            rules,
+            vec![AuditRule::MissingH1, AuditRule::OrphanedSitemapUrl]
+        );
+    }
+
+    #[test]
+    fn test_truncated_page() {
+        let mut site = Site::new();
+        site.metadata("/a").title = None;
+        site.metadata("/a").h1s.clear();
+        site.run.pages.get_mut(&url("/a")).unwrap().truncated = true;
+
+        // its title and heading may be past where it was cut off
+        let rules: Vec<AuditRule> = site
+            .audit(&AuditOptions::default())
+            .into_iter()
+            .map(|f| f.0)
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                AuditRule::OrphanedSitemapUrl,
+                AuditRule::NoindexInNavigation
+            ]
+        );
+    }
+}
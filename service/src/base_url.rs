@@ -1,14 +1,90 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use shared::CrawlOptions;
+
+use crate::{
+    filter::UrlFilter,
+    recrawl::Recrawl,
+    run::{start_run, CrawlRun, RunPage},
+};
+
 pub(crate) struct BaseUrl {
     crawling: bool,
+    options: CrawlOptions,
+    filter: Option<UrlFilter>,
+    recrawl: Option<Recrawl>,
+    excluded_urls: HashMap<Url, String>,
+    stats: CrawlStats,
+    // the latest last, each started by a `start`
+    runs: Vec<CrawlRun>,
+}
+
+// Counts of the fetches made since the crawl last started
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub(crate) struct CrawlStats {
+    pub(crate) pages_fetched: u64,
+    // answered with `304 Not Modified`, so the page wasn't downloaded or parsed again
+    pub(crate) not_modified: u64,
+}
+
+impl CrawlStats {
+    pub(crate) fn count_fetch(&mut self, not_modified: bool) {
+        self.pages_fetched += 1;
+        if not_modified {
+            self.not_modified += 1;
+        }
+    }
 }
 
 impl BaseUrl {
     pub(crate) fn new() -> Self {
-        Self { crawling: false }
+        Self {
+            crawling: false,
+            options: CrawlOptions::default(),
+            filter: None,
+            recrawl: None,
+            excluded_urls: HashMap::new(),
+            stats: CrawlStats::default(),
+            runs: Vec::new(),
+        }
+    }
+
+    pub(crate) fn restore(
+        crawling: bool,
+        options: CrawlOptions,
+        filter: Option<UrlFilter>,
+        recrawl: Option<Recrawl>,
+        excluded_urls: HashMap<Url, String>,
+        stats: CrawlStats,
+        runs: Vec<CrawlRun>,
+    ) -> Self {
+        Self {
+            crawling,
+            options,
+            filter,
+            recrawl,
+            excluded_urls,
+            stats,
+            runs,
+        }
     }
 
-    pub(crate) fn start_crawling(&mut self) {
+    pub(crate) fn start_crawling(
+        &mut self,
+        start_url: Url,
+        options: CrawlOptions,
+        filter: UrlFilter,
+        recrawl: Recrawl,
+    ) {
+        start_run(&mut self.runs, recrawl.run_started_at(), start_url);
         self.crawling = true;
+        self.options = options;
+        self.filter = Some(filter);
+        self.recrawl = Some(recrawl);
+        self.stats = CrawlStats::default();
     }
 
     pub(crate) fn stop_crawling(&mut self) {
@@ -18,4 +94,67 @@ impl BaseUrl {
     pub(crate) fn is_crawling(&self) -> bool {
         self.crawling
     }
+
+    pub(crate) fn options(&self) -> &CrawlOptions {
+        &self.options
+    }
+
+    pub(crate) fn recrawl(&self) -> Option<&Recrawl> {
+        self.recrawl.as_ref()
+    }
+
+    // Whether the link passes the crawl's filters. They're matched against the link as it was
+    // written, so `/wp-admin/*` still matches `/wp-admin/`, and exclusions are recorded under
+    // the URL it's crawled as.
+    pub(crate) fn filter_url(&mut self, link_url: &Url, url: &Url) -> bool {
+        let Some(filter) = &self.filter else {
+            return true;
+        };
+        match filter.exclusion_reason(link_url) {
+            Some(reason) => {
+                self.excluded_urls.insert(url.clone(), reason);
+                false
+            }
+            None => true,
+        }
+    }
+
+    pub(crate) fn excluded_urls(&self) -> &HashMap<Url, String> {
+        &self.excluded_urls
+    }
+
+    pub(crate) fn stats(&self) -> &CrawlStats {
+        &self.stats
+    }
+
+    pub(crate) fn stats_mut(&mut self) -> &mut CrawlStats {
+        &mut self.stats
+    }
+
+    pub(crate) fn runs(&self) -> &[CrawlRun] {
+        &self.runs
+    }
+
+    // The page as the newest run that fetched it successfully found it
+    pub(crate) fn last_run_page(&self, url: &Url) -> Option<&RunPage> {
+        self.runs
+            .iter()
+            .rev()
+            .filter_map(|run| run.pages.get(url))
+            .find(|page| !page.status.is_broken())
+    }
+
+    // Records the page's latest fetch in the current run
+    pub(crate) fn record_run_page(&mut self, url: Url, page: RunPage) {
+        if let Some(run) = self.runs.last_mut() {
+            run.pages.insert(url, page);
+        }
+    }
+
+    // Records the URLs the site's sitemaps listed at the start of the current run
+    pub(crate) fn record_sitemap(&mut self, urls: Vec<Url>) {
+        if let Some(run) = self.runs.last_mut() {
+            run.sitemap_urls = urls;
+        }
+    }
 }
@@ -0,0 +1,289 @@
+use std::collections::HashSet;
+
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1252, X_USER_DEFINED};
+
+// How far into a document a `<meta charset>` is looked for
+pub(crate) const PRESCAN_LENGTH: usize = 1024;
+
+// The encoding declared by a `<meta>` element at the start of the document, following the
+// WHATWG "prescan a byte stream to determine its encoding" algorithm
+pub(crate) fn prescan(bytes: &[u8]) -> Option<&'static Encoding> {
+    let bytes = &bytes[..bytes.len().min(PRESCAN_LENGTH)];
+    let mut position = 0;
+    while position < bytes.len() {
+        let rest = &bytes[position..];
+        if rest.starts_with(b"<!--") {
+            // the comment's end may share its dashes with the start, as in `<!-->`
+            position += 2 + find(&rest[2..], b"-->")? + 3;
+        } else if starts_with_ignore_case(rest, b"<meta")
+            && rest
+                .get(5)
+                .is_some_and(|&byte| is_space(byte) || byte == b'/')
+        {
+            position += 6;
+            if let Some(encoding) = read_meta_attributes(bytes, &mut position) {
+                return Some(encoding);
+            }
+            position += 1;
+        } else if rest.starts_with(b"<")
+            && (rest.get(1).is_some_and(u8::is_ascii_alphabetic)
+                || (rest.get(1) == Some(&b'/') && rest.get(2).is_some_and(u8::is_ascii_alphabetic)))
+        {
+            // skip other tags, along with their attributes
+            position += 1;
+            while position < bytes.len() && !is_space(bytes[position]) && bytes[position] != b'>' {
+                position += 1;
+            }
+            while read_attribute(bytes, &mut position).is_some() {}
+            position += 1;
+        } else if rest.starts_with(b"<!") || rest.starts_with(b"</") || rest.starts_with(b"<?") {
+            position += find(rest, b">")? + 1;
+        } else {
+            position += 1;
+        }
+    }
+    None
+}
+
+fn read_meta_attributes(bytes: &[u8], position: &mut usize) -> Option<&'static Encoding> {
+    let mut attribute_names = HashSet::new();
+    let mut got_pragma = false;
+    let mut need_pragma = None;
+    let mut charset = None;
+
+    while let Some((name, value)) = read_attribute(bytes, position) {
+        if !attribute_names.insert(name.clone()) {
+            continue;
+        }
+        match name.as_slice() {
+            b"http-equiv" => got_pragma |= value == b"content-type",
+            b"content" => {
+                if let (None, Some(encoding)) = (charset, charset_from_meta_content(&value)) {
+                    charset = Some(encoding);
+                    need_pragma = Some(true);
+                }
+            }
+            b"charset" => {
+                charset = Encoding::for_label(&value);
+                need_pragma = Some(false);
+            }
+            _ => {}
+        }
+    }
+
+    match need_pragma {
+        None => None,
+        Some(true) if !got_pragma => None,
+        _ => charset.map(document_encoding),
+    }
+}
+
+// The encoding named by a `<meta http-equiv="Content-Type">` content value, e.g.
+// `text/html; charset=Shift_JIS`
+pub(crate) fn charset_from_meta_content(content: &[u8]) -> Option<&'static Encoding> {
+    let mut position = 0;
+    loop {
+        position += find_ignore_case(&content[position..], b"charset")? + b"charset".len();
+        let after_name = skip_spaces(content, position);
+        if content.get(after_name) != Some(&b'=') {
+            position = after_name;
+            continue;
+        }
+        let value_start = skip_spaces(content, after_name + 1);
+        let value = &content[value_start..];
+        return match value.first() {
+            Some(&quote @ (b'"' | b'\'')) => {
+                let end = find(&value[1..], &[quote])?;
+                Encoding::for_label(&value[1..1 + end])
+            }
+            Some(_) => {
+                let end = value
+                    .iter()
+                    .position(|&byte| is_space(byte) || byte == b';')
+                    .unwrap_or(value.len());
+                Encoding::for_label(&value[..end])
+            }
+            None => None,
+        };
+    }
+}
+
+// A declared encoding that can't be right for a document that was readable enough to declare
+// it is replaced, as browsers do
+fn document_encoding(encoding: &'static Encoding) -> &'static Encoding {
+    if encoding == X_USER_DEFINED {
+        WINDOWS_1252
+    } else if encoding.output_encoding() == UTF_8 {
+        // UTF-16 declared in markup that was readable as ASCII
+        UTF_8
+    } else {
+        encoding
+    }
+}
+
+// The WHATWG "get an attribute" algorithm: the next attribute's lowercased name and value, or
+// `None` at the end of the tag
+fn read_attribute(bytes: &[u8], position: &mut usize) -> Option<(Vec<u8>, Vec<u8>)> {
+    while *position < bytes.len() && (is_space(bytes[*position]) || bytes[*position] == b'/') {
+        *position += 1;
+    }
+    if *bytes.get(*position)? == b'>' {
+        return None;
+    }
+
+    let mut name = Vec::new();
+    let mut value = Vec::new();
+    loop {
+        let byte = *bytes.get(*position)?;
+        if byte == b'=' && !name.is_empty() {
+            *position += 1;
+            break;
+        }
+        if is_space(byte) {
+            *position = skip_spaces(bytes, *position);
+            if bytes.get(*position) != Some(&b'=') {
+                return Some((name, value));
+            }
+            *position += 1;
+            break;
+        }
+        if byte == b'/' || byte == b'>' {
+            return Some((name, value));
+        }
+        name.push(byte.to_ascii_lowercase());
+        *position += 1;
+    }
+
+    *position = skip_spaces(bytes, *position);
+    match *bytes.get(*position)? {
+        quote @ (b'"' | b'\'') => {
+            *position += 1;
+            loop {
+                let byte = *bytes.get(*position)?;
+                *position += 1;
+                if byte == quote {
+                    return Some((name, value));
+                }
+                value.push(byte.to_ascii_lowercase());
+            }
+        }
+        b'>' => Some((name, value)),
+        _ => {
+            while let Some(&byte) = bytes.get(*position) {
+                if is_space(byte) || byte == b'>' {
+                    return Some((name, value));
+                }
+                value.push(byte.to_ascii_lowercase());
+                *position += 1;
+            }
+            None
+        }
+    }
+}
+
+fn is_space(byte: u8) -> bool {
+    matches!(byte, b'\t' | b'\n' | b'\x0c' | b'\r' | b' ')
+}
+
+fn skip_spaces(bytes: &[u8], mut position: usize) -> usize {
+    while position < bytes.len() && is_space(bytes[position]) {
+        position += 1;
+    }
+    position
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn find_ignore_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle))
+}
+
+fn starts_with_ignore_case(bytes: &[u8], prefix: &[u8]) -> bool {
+    bytes.len() >= prefix.len() && bytes[..prefix.len()].eq_ignore_ascii_case(prefix)
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use encoding_rs::{SHIFT_JIS, WINDOWS_1251};
+
+    fn prescan_name(html: &str) -> Option<&'static str> {
+        prescan(html.as_bytes()).map(|encoding| encoding.name())
+    }
+
+    #[test]
+    fn test_meta_charset() {
+        assert_eq!(
+            prescan_name(r#"<meta charset="windows-1252">"#),
+            Some("windows-1252")
+        );
+        assert_eq!(prescan_name("<META CHARSET=Shift_JIS>"), Some("Shift_JIS"));
+        assert_eq!(
+            prescan_name("<meta charset='latin1'/>"),
+            Some("windows-1252")
+        );
+        assert_eq!(prescan_name(r#"<meta charset="bogus">"#), None);
+        assert_eq!(prescan_name("<p>no declaration</p>"), None);
+    }
+
+    #[test]
+    fn test_meta_http_equiv() {
+        let html = r#"<meta http-equiv="Content-Type" content="text/html; charset=windows-1251">"#;
+        assert_eq!(prescan(html.as_bytes()), Some(WINDOWS_1251));
+        let html = r#"<meta content="text/html; charset=shift_jis" http-equiv=content-type>"#;
+        assert_eq!(prescan(html.as_bytes()), Some(SHIFT_JIS));
+        // a content charset only counts along with the pragma
+        let html = r#"<meta content="text/html; charset=windows-1251">"#;
+        assert_eq!(prescan(html.as_bytes()), None);
+    }
+
+    #[test]
+    fn test_skipped_markup() {
+        let html = r#"<!-- <meta charset="windows-1251"> --><meta charset="shift_jis">"#;
+        assert_eq!(prescan(html.as_bytes()), Some(SHIFT_JIS));
+        let html = r#"<div title='<meta charset="windows-1251">'><meta charset="shift_jis">"#;
+        assert_eq!(prescan(html.as_bytes()), Some(SHIFT_JIS));
+        let html = r#"<!--><meta charset="shift_jis">"#;
+        assert_eq!(prescan(html.as_bytes()), Some(SHIFT_JIS));
+    }
+
+    #[test]
+    fn test_only_the_start_is_scanned() {
+        let html = format!(
+            r#"{}<meta charset="shift_jis">"#,
+            " ".repeat(PRESCAN_LENGTH)
+        );
+        assert_eq!(prescan(html.as_bytes()), None);
+    }
+
+    #[test]
+    fn test_declared_encodings_are_replaced() {
+        assert_eq!(prescan(br#"<meta charset="utf-16le">"#), Some(UTF_8));
+        assert_eq!(
+            prescan(br#"<meta charset="x-user-defined">"#),
+            Some(WINDOWS_1252)
+        );
+    }
+
+    #[test]
+    fn test_charset_from_meta_content() {
+        let charset = |content: &str| charset_from_meta_content(content.as_bytes());
+        assert_eq!(charset("text/html; charset=Shift_JIS"), Some(SHIFT_JIS));
+        assert_eq!(
+            charset("text/html;CHARSET = \"windows-1251\""),
+            Some(WINDOWS_1251)
+        );
+        assert_eq!(charset("charsetx; charset='shift_jis'"), Some(SHIFT_JIS));
+        assert_eq!(charset("text/html; charset=\"shift_jis"), None);
+        assert_eq!(charset("text/html"), None);
+    }
+}
@@ -1,35 +1,315 @@
 use std::{
     collections::{HashMap, HashSet},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
 use anyhow::Result;
-use log::{info, warn};
+use chrono::Local;
+use log::info;
 use tokio::sync::RwLock;
 use url::Url;
 
-use shared::Command;
+use shared::{AuditOptions, Command, CrawlOptions, LinkEdge, LinkKind, Response};
 
 use crate::{
+    audit::audit,
     base_url::BaseUrl,
-    error::{print_error, CrawlerError},
+    error::{print_error, print_error_and_backtrace, CrawlerError},
+    external::ExternalChecker,
+    filter::UrlFilter,
+    node::Node,
+    parse_pool::ParsePool,
+    recrawl::Recrawl,
+    run::{broken_links, diff_report, RunPage},
+    scope::{is_in_path_scope, is_in_scope, path_scope_prefix, with_crawl_scheme},
+    seed_url::{resolve_seed_url, seed_url_candidates},
+    sitemap::fetch_sitemap_urls,
+    store::{PageLink, SavedState, StateEvent, Store},
     url_worker::UrlWorker,
 };
 
+// How often the recrawl scheduler looks for pages that are due
+const RECRAWL_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+// How long a server gets to accept a connection, and to send a whole page
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(120);
+
 #[derive(Clone)]
 pub struct Crawler {
     base_urls: Arc<RwLock<HashMap<Url, BaseUrl>>>,
     url_workers: Arc<RwLock<HashMap<Url, Arc<RwLock<UrlWorker>>>>>,
     url_parents: Arc<RwLock<HashMap<Url, HashSet<Url>>>>,
+    url_nodes: Arc<RwLock<HashMap<Url, Node>>>,
+    url_links: Arc<RwLock<HashMap<Url, Vec<PageLink>>>>,
+    http_client: reqwest::Client,
+    parse_pool: ParsePool,
+    external_checker: ExternalChecker,
+    store: Store,
+    // the id of the next job spawned, which pairs its `Enqueued` with its `Done`
+    next_job_id: Arc<AtomicU64>,
 }
 
 impl Crawler {
-    pub fn new() -> Self {
+    fn new(store: Store) -> Self {
         Self {
             base_urls: Arc::new(RwLock::new(HashMap::new())),
             url_workers: Arc::new(RwLock::new(HashMap::new())),
             url_parents: Arc::new(RwLock::new(HashMap::new())),
+            url_nodes: Arc::new(RwLock::new(HashMap::new())),
+            url_links: Arc::new(RwLock::new(HashMap::new())),
+            http_client: reqwest::Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .timeout(FETCH_TIMEOUT)
+                // redirects are followed by the fetcher, which keeps track of them
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap_or_default(),
+            parse_pool: ParsePool::new(),
+            external_checker: ExternalChecker::new(),
+            store,
+            next_job_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    // Rebuilds the crawler from the state saved before the daemon last stopped, and resumes
+    // the crawls that were still running
+    pub(crate) async fn restore(store: Store, saved_state: SavedState) -> Self {
+        let crawler = Self::new(store);
+
+        let mut base_urls = crawler.base_urls.write().await;
+        for (base_url, site) in saved_state.sites {
+            // the filter and recrawl policy were valid when the crawl started
+            let filter = UrlFilter::new(&site.options.filters).ok();
+            let recrawl = Recrawl::new(&site.options.recrawl, site.started_at).ok();
+            let base_url_record = BaseUrl::restore(
+                site.crawling,
+                site.options,
+                filter,
+                recrawl,
+                site.excluded_urls,
+                site.stats,
+                site.runs,
+            );
+            base_urls.insert(base_url, base_url_record);
+        }
+        drop(base_urls);
+
+        *crawler.url_parents.write().await = saved_state.url_parents;
+        *crawler.url_nodes.write().await = saved_state.url_nodes;
+        *crawler.url_links.write().await = saved_state.url_links;
+
+        let mut url_workers = crawler.url_workers.write().await;
+        for (url, worker) in saved_state.workers {
+            let crawl_job = UrlWorker::restore(
+                crawler.clone(),
+                worker.base_url,
+                url.clone(),
+                worker.history,
+            );
+            url_workers.insert(url, Arc::new(RwLock::new(crawl_job)));
+        }
+        drop(url_workers);
+
+        let next_job_id = saved_state.frontier.keys().max().map_or(0, |id| id + 1);
+        crawler.next_job_id.store(next_job_id, Ordering::Relaxed);
+        let mut resumed_count = 0;
+        for (id, job) in saved_state.frontier {
+            if crawler.base_url_options(&job.base_url).await.is_none() {
+                continue;
+            }
+            // still queued under the same id, so it's done once it's crawled
+            crawler.run_job(id, job.base_url, job.parent_url, job.url);
+            resumed_count += 1;
+        }
+        if resumed_count > 0 {
+            info!("Resuming crawls with {} queued URLs", resumed_count);
+        }
+
+        crawler
+    }
+
+    // Fetches pages again as their site's recrawl policy comes due, rather than waiting for
+    // another page to link to them
+    pub(crate) fn start_recrawl_scheduler(&self) {
+        let self_clone = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RECRAWL_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                self_clone.schedule_recrawls().await;
+            }
+        });
+    }
+
+    async fn schedule_recrawls(&self) {
+        let now = Local::now();
+        let workers: Vec<(Url, Arc<RwLock<UrlWorker>>)> = self
+            .url_workers
+            .read()
+            .await
+            .iter()
+            .map(|(url, worker)| (url.clone(), worker.clone()))
+            .collect();
+
+        let mut recrawls: HashMap<Url, Option<Recrawl>> = HashMap::new();
+        let mut due_counts: HashMap<Url, usize> = HashMap::new();
+        for (url, worker) in workers {
+            // pages being fetched right now aren't due
+            let Ok(worker) = worker.try_read() else {
+                continue;
+            };
+            let base_url = worker.base_url().clone();
+            if !recrawls.contains_key(&base_url) {
+                let recrawl = self.base_url_recrawl(&base_url).await;
+                recrawls.insert(base_url.clone(), recrawl);
+            }
+            let Some(recrawl) = &recrawls[&base_url] else {
+                continue;
+            };
+            if !recrawl.is_recrawl_due(worker.history(), now) {
+                continue;
+            }
+            drop(worker);
+
+            *due_counts.entry(base_url.clone()).or_default() += 1;
+            self.spawn_job(base_url, None, url);
+        }
+
+        for (base_url, due_count) in due_counts {
+            info!("Recrawling {} pages of {}", due_count, base_url);
+        }
+    }
+
+    pub(crate) fn store(&self) -> &Store {
+        &self.store
+    }
+
+    pub(crate) fn http_client(&self) -> &reqwest::Client {
+        &self.http_client
+    }
+
+    pub(crate) fn parse_pool(&self) -> &ParsePool {
+        &self.parse_pool
+    }
+
+    pub(crate) async fn update_node(&self, url: &Url, update: impl FnOnce(&mut Node)) {
+        let mut map = self.url_nodes.write().await;
+        let node = map.entry(url.clone()).or_default();
+        update(node);
+        self.store.record(StateEvent::NodeUpdated {
+            url: url.clone(),
+            node: node.clone(),
+        });
+    }
+
+    pub(crate) async fn node(&self, url: &Url) -> Option<Node> {
+        let map = self.url_nodes.read().await;
+        map.get(url).cloned()
+    }
+
+    pub(crate) async fn page_links(&self, url: &Url) -> Vec<PageLink> {
+        let map = self.url_links.read().await;
+        map.get(url).cloned().unwrap_or_default()
+    }
+
+    pub(crate) async fn count_fetch(&self, base_url: &Url, not_modified: bool) {
+        let mut map = self.base_urls.write().await;
+        if let Some(base_url_record) = map.get_mut(base_url) {
+            base_url_record.stats_mut().count_fetch(not_modified);
+        }
+    }
+
+    pub(crate) async fn last_run_page(&self, base_url: &Url, url: &Url) -> Option<RunPage> {
+        let map = self.base_urls.read().await;
+        map.get(base_url)?.last_run_page(url).cloned()
+    }
+
+    pub(crate) async fn record_run_page(&self, base_url: &Url, url: &Url, page: RunPage) {
+        let mut map = self.base_urls.write().await;
+        if let Some(base_url_record) = map.get_mut(base_url) {
+            base_url_record.record_run_page(url.clone(), page.clone());
+        }
+        self.store.record(StateEvent::RunPageRecorded {
+            base_url: base_url.clone(),
+            url: url.clone(),
+            page,
+        });
+    }
+
+    // Records the URLs in the crawl's scope that its site's sitemaps list, for the current run
+    async fn read_sitemaps(&self, base_url: &Url) {
+        let Some(options) = self.base_url_options(base_url).await else {
+            return;
+        };
+        let mut urls: Vec<Url> = fetch_sitemap_urls(&self.http_client, base_url)
+            .await
+            .into_iter()
+            .filter(|url| is_in_scope(&options.scope, base_url, url))
+            .filter(|url| is_in_path_scope(base_url, url))
+            .map(strip_url_to_domain_and_path)
+            .collect();
+        urls.sort();
+        urls.dedup();
+        info!("Found {} URLs in the sitemaps of {}", urls.len(), base_url);
+
+        let mut map = self.base_urls.write().await;
+        if let Some(base_url_record) = map.get_mut(base_url) {
+            base_url_record.record_sitemap(urls.clone());
         }
+        self.store.record(StateEvent::SitemapRecorded {
+            base_url: base_url.clone(),
+            urls,
+        });
+    }
+
+    pub(crate) async fn clear_links(&self, url: &Url) {
+        let mut map = self.url_links.write().await;
+        map.remove(url);
+        self.store.record(StateEvent::LinksCleared {
+            page_url: url.clone(),
+        });
+    }
+
+    pub(crate) async fn record_link(&self, page_url: &Url, link_url: &Url, edge: LinkEdge) {
+        let mut map = self.url_links.write().await;
+        let links = map.entry(page_url.clone()).or_default();
+        links.push((link_url.clone(), edge.clone()));
+        self.store.record(StateEvent::LinkRecorded {
+            page_url: page_url.clone(),
+            url: link_url.clone(),
+            edge,
+        });
+    }
+
+    // Crawls the URL in the background. It's recorded as queued first, so it's picked up again
+    // if the daemon stops before it's done.
+    pub(crate) fn spawn_job(&self, base_url: Url, prev_url_opt: Option<Url>, url: Url) {
+        let id = self.next_job_id.fetch_add(1, Ordering::Relaxed);
+        self.store.record(StateEvent::Enqueued {
+            id,
+            base_url: base_url.clone(),
+            parent_url: prev_url_opt.clone(),
+            url: url.clone(),
+        });
+        self.run_job(id, base_url, prev_url_opt, url);
+    }
+
+    // Crawls a queued URL in the background, and records its job as done after
+    fn run_job(&self, id: u64, base_url: Url, prev_url_opt: Option<Url>, url: Url) {
+        let self_clone = self.clone();
+        tokio::spawn(async move {
+            let result = self_clone
+                .start_job(&base_url, prev_url_opt.as_ref(), &url)
+                .await;
+            self_clone.store.record(StateEvent::Done { id });
+            if let Err(err) = result {
+                print_error_and_backtrace(err);
+            }
+        });
     }
 
     async fn has_worker(&self, url: &Url) -> bool {
@@ -37,43 +317,85 @@ impl Crawler {
         map.contains_key(url)
     }
 
-    async fn create_worker(&self, prev_url_opt: Option<&Url>, url: &Url) -> Result<()> {
+    async fn is_in_tree(&self, url: &Url) -> bool {
+        let map = self.url_parents.read().await;
+        map.contains_key(url)
+    }
+
+    async fn create_worker(
+        &self,
+        base_url: &Url,
+        prev_url_opt: Option<&Url>,
+        url: &Url,
+    ) -> Result<()> {
         let self_clone = self.clone();
 
-        // Add to children
+        self.add_child(base_url, prev_url_opt, url).await?;
+
+        // Create new worker
+        let mut map = self.url_workers.write().await;
+        let crawl_job = UrlWorker::new(self_clone, base_url, url)?;
+        map.insert(url.clone(), Arc::new(RwLock::new(crawl_job)));
+        self.store.record(StateEvent::WorkerCreated {
+            base_url: base_url.clone(),
+            url: url.clone(),
+        });
+
+        self.register_parent(url).await;
+
+        Ok(())
+    }
+
+    async fn create_leaf(
+        &self,
+        base_url: &Url,
+        prev_url_opt: Option<&Url>,
+        url: &Url,
+    ) -> Result<()> {
+        self.add_child(base_url, prev_url_opt, url).await?;
+        self.register_parent(url).await;
+        Ok(())
+    }
+
+    async fn add_child(&self, base_url: &Url, prev_url_opt: Option<&Url>, url: &Url) -> Result<()> {
         if let Some(prev_url) = prev_url_opt {
             // Parent is UrlWorker
             let mut map = self.url_parents.write().await;
             if let Some(children) = map.get_mut(prev_url) {
                 children.insert(url.clone());
+                self.store.record(StateEvent::ChildAdded {
+                    parent_url: prev_url.clone(),
+                    url: url.clone(),
+                });
             } else {
                 return Err(CrawlerError::ParentUrlWorkerNotFound(prev_url.to_string()).into());
             }
         } else {
             // Parent is BaseUrl
-            let base_url = strip_url_to_domain(url.clone());
             if url.as_str() != base_url.as_str() {
                 let mut map = self.url_parents.write().await;
-                if let Some(children) = map.get_mut(&base_url) {
+                if let Some(children) = map.get_mut(base_url) {
                     children.insert(url.clone());
+                    self.store.record(StateEvent::ChildAdded {
+                        parent_url: base_url.clone(),
+                        url: url.clone(),
+                    });
                 } else {
                     return Err(CrawlerError::BaseUrlNotFound(url.to_string()).into());
                 }
             }
         }
 
-        // Create new worker
-        let mut map = self.url_workers.write().await;
-        let crawl_job = UrlWorker::new(self_clone, url)?;
-        map.insert(url.clone(), Arc::new(RwLock::new(crawl_job)));
+        Ok(())
+    }
 
-        // Register the parent
+    async fn register_parent(&self, url: &Url) {
         let mut map = self.url_parents.write().await;
         if !map.contains_key(url) {
             map.insert(url.clone(), HashSet::new());
+            self.store
+                .record(StateEvent::NodeAdded { url: url.clone() });
         }
-
-        Ok(())
     }
 
     async fn get_worker(&self, url: &Url) -> Option<Arc<RwLock<UrlWorker>>> {
@@ -81,96 +403,245 @@ impl Crawler {
         map.get(url).cloned()
     }
 
-    async fn base_url_is_crawling(&self, url: &Url) -> bool {
-        let base_url = strip_url_to_domain(url.clone());
+    async fn base_url_options(&self, base_url: &Url) -> Option<CrawlOptions> {
         let map = self.base_urls.read().await;
-        if let Some(base_url_record) = map.get(&base_url) {
-            base_url_record.is_crawling()
+        let base_url_record = map.get(base_url)?;
+        if base_url_record.is_crawling() {
+            Some(base_url_record.options().clone())
         } else {
-            false
+            None
         }
     }
 
-    async fn base_url_start_crawling(&self, url: &Url) {
-        let base_url = strip_url_to_domain(url.clone());
+    async fn base_url_recrawl(&self, base_url: &Url) -> Option<Recrawl> {
+        let map = self.base_urls.read().await;
+        let base_url_record = map.get(base_url)?;
+        if base_url_record.is_crawling() {
+            base_url_record.recrawl().cloned()
+        } else {
+            None
+        }
+    }
+
+    async fn base_url_filter_url(&self, base_url: &Url, link_url: &Url, url: &Url) -> bool {
+        let mut map = self.base_urls.write().await;
+        let Some(base_url_record) = map.get_mut(base_url) else {
+            return false;
+        };
+        if base_url_record.filter_url(&strip_fragment(link_url.clone()), url) {
+            return true;
+        }
+        if let Some(reason) = base_url_record.excluded_urls().get(url) {
+            self.store.record(StateEvent::UrlExcluded {
+                base_url: base_url.clone(),
+                url: url.clone(),
+                reason: reason.clone(),
+            });
+        }
+        false
+    }
+
+    async fn base_url_start_crawling(
+        &self,
+        url: &Url,
+        options: CrawlOptions,
+        filter: UrlFilter,
+        recrawl: Recrawl,
+    ) -> Url {
+        let base_url = strip_url_to_site_root(url.clone(), options.path_scope);
         let mut map = self.base_urls.write().await;
         if !map.contains_key(&base_url) {
             map.insert(base_url.clone(), BaseUrl::new());
         }
-        map.get_mut(&base_url).unwrap().start_crawling();
+        self.store.record(StateEvent::SiteStarted {
+            base_url: base_url.clone(),
+            start_url: url.clone(),
+            options: options.clone(),
+            started_at: recrawl.run_started_at(),
+        });
+        map.get_mut(&base_url)
+            .unwrap()
+            .start_crawling(url.clone(), options, filter, recrawl);
+        drop(map);
 
         // Register the parent
-        let mut map = self.url_parents.write().await;
-        if !map.contains_key(&base_url) {
-            map.insert(base_url.clone(), HashSet::new());
-        }
+        self.register_parent(&base_url).await;
+
+        base_url
     }
 
-    async fn base_url_stop_crawling(&self, url: &Url) {
-        let base_url = strip_url_to_domain(url.clone());
+    async fn base_url_stop_crawling(&self, url: &Url) -> Option<Url> {
         let mut map = self.base_urls.write().await;
-        if !map.contains_key(&base_url) {
-            warn!("Url not found: {}", base_url);
-            return;
-        }
+        let base_url = find_base_url(&map, url)?;
         map.get_mut(&base_url).unwrap().stop_crawling();
+        self.store.record(StateEvent::SiteStopped {
+            base_url: base_url.clone(),
+        });
+
+        info!("Stopping crawling for {}", base_url);
+
+        Some(base_url)
     }
 
-    pub async fn handle_command(&self, command: Command) -> Result<()> {
+    pub async fn handle_command(&self, command: Command) -> Result<Response> {
         match command {
-            Command::Start(url) => self.handle_command_start(&url).await,
+            Command::Start(url, options) => self.handle_command_start(&url, options).await,
             Command::Stop(url) => self.handle_command_stop(&url).await,
             Command::List => self.handle_command_list().await,
+            Command::Diff(url, run_a, run_b) => self.handle_command_diff(&url, run_a, run_b).await,
+            Command::Broken(url) => self.handle_command_broken(&url).await,
+            Command::Show(url) => self.handle_command_show(&url).await,
+            Command::Audit(url, options) => self.handle_command_audit(&url, &options).await,
         }
     }
 
-    async fn handle_command_start(&self, url_str: &str) -> Result<()> {
-        let url = Url::parse(url_str)?;
+    async fn handle_command_start(&self, url_str: &str, options: CrawlOptions) -> Result<Response> {
+        let filter = UrlFilter::new(&options.filters)?;
+        let recrawl = Recrawl::new(&options.recrawl, Local::now())?;
+        let url = resolve_seed_url(url_str).await?;
 
-        self.base_url_start_crawling(&url).await;
+        let base_url = self
+            .base_url_start_crawling(&url, options, filter, recrawl)
+            .await;
 
-        // start crawling
-        self.start_job(None, &url).await?;
+        // start crawling in the background, so the client gets the resolved url right away
+        self.spawn_job(base_url.clone(), None, url.clone());
 
-        Ok(())
+        // the sitemaps are only needed by audits, so they're read alongside the crawl
+        let self_clone = self.clone();
+        tokio::spawn(async move { self_clone.read_sitemaps(&base_url).await });
+
+        Ok(Response::Started(url.to_string()))
     }
 
-    pub(crate) async fn start_job(&self, prev_url_opt: Option<&Url>, url: &Url) -> Result<()> {
-        let url = strip_url_to_domain_and_path(url.clone());
+    pub(crate) async fn start_job(
+        &self,
+        base_url: &Url,
+        prev_url_opt: Option<&Url>,
+        link_url: &Url,
+    ) -> Result<()> {
+        let url = strip_url_to_domain_and_path(link_url.clone());
 
-        if !self.base_url_is_crawling(&url).await {
+        let (Some(options), Some(recrawl)) = (
+            self.base_url_options(base_url).await,
+            self.base_url_recrawl(base_url).await,
+        ) else {
             let crawler_error = CrawlerError::BaseUrlHasStoppedCrawling(
                 url.path().to_string(),
-                strip_url_to_domain(url.clone()).to_string(),
+                base_url.to_string(),
             );
             print_error(crawler_error.clone().into());
             return Ok(());
+        };
+
+        if !is_in_scope(&options.scope, base_url, &url) {
+            if options.external.check {
+                // another site's query and trailing slash may well matter to it
+                let external_url = strip_fragment(link_url.clone());
+                self.check_external_link(base_url, prev_url_opt, &external_url, &options)
+                    .await?;
+            } else {
+                CrawlerError::LinkUrlDoesNotMatchBaseUrl(url.to_string(), base_url.to_string())
+                    .print();
+            }
+            return Ok(());
+        }
+        let url = with_crawl_scheme(&options.scope, base_url, url);
+
+        if !is_in_path_scope(base_url, &url) {
+            // same site but outside of the crawled path, so record it without fetching. A page
+            // another crawl of the site fetched stays in that crawl's tree alone.
+            if !self.has_worker(&url).await {
+                self.create_leaf(base_url, prev_url_opt, &url).await?;
+            }
+            return Ok(());
+        }
+
+        // the start url is always crawled, links are checked against the include/exclude rules
+        if prev_url_opt.is_some() && !self.base_url_filter_url(base_url, link_url, &url).await {
+            return Ok(());
         }
 
         // check if job already exists
         if !self.has_worker(&url).await {
-            self.create_worker(prev_url_opt, &url).await?;
+            self.create_worker(base_url, prev_url_opt, &url).await?;
         }
 
         // start the job
         let job = self.get_worker(&url).await.unwrap();
-        job.write().await.start().await?;
+        job.write()
+            .await
+            .start(base_url, &options, &recrawl)
+            .await?;
 
         Ok(())
     }
 
-    async fn handle_command_stop(&self, url_str: &str) -> Result<()> {
-        let url = Url::parse(url_str)?;
-        let url = strip_url_to_domain(url);
+    // Records a link leaving the crawled site as a leaf, and checks whether it works without
+    // following it
+    async fn check_external_link(
+        &self,
+        base_url: &Url,
+        prev_url_opt: Option<&Url>,
+        url: &Url,
+        options: &CrawlOptions,
+    ) -> Result<()> {
+        self.create_leaf(base_url, prev_url_opt, url).await?;
+        self.update_node(url, |node| node.external = true).await;
 
-        self.base_url_stop_crawling(&url).await;
+        let status = self.external_checker.check(url, &options.external).await;
+        self.update_node(url, |node| node.link_status = Some(status.clone()))
+            .await;
+        self.record_run_page(base_url, url, RunPage::unparsed(status))
+            .await;
+        Ok(())
+    }
+
+    // Records a resource referenced by a page (an image, script, ...) without fetching it
+    pub(crate) async fn record_asset(
+        &self,
+        base_url: &Url,
+        prev_url: &Url,
+        url: &Url,
+        kind: LinkKind,
+    ) -> Result<()> {
+        let url = strip_url_to_domain_and_path(url.clone());
+
+        let Some(options) = self.base_url_options(base_url).await else {
+            return Ok(());
+        };
+        if !is_in_scope(&options.scope, base_url, &url) {
+            CrawlerError::LinkUrlDoesNotMatchBaseUrl(url.to_string(), base_url.to_string()).print();
+            return Ok(());
+        }
+        let url = with_crawl_scheme(&options.scope, base_url, url);
+        // a page referencing itself or another page already in the tree, such as through its
+        // canonical or a form, mustn't become a child of it
+        if url == *prev_url || self.has_worker(&url).await || self.is_in_tree(&url).await {
+            return Ok(());
+        }
 
-        info!("Stopping crawling for {}", url);
+        self.create_leaf(base_url, Some(prev_url), &url).await?;
+        self.update_node(&url, |node| {
+            node.asset_kind.get_or_insert(kind);
+        })
+        .await;
 
         Ok(())
     }
 
-    async fn handle_command_list(&self) -> Result<()> {
+    async fn handle_command_stop(&self, url_str: &str) -> Result<Response> {
+        // bare hosts match a crawl of either scheme
+        for url in seed_url_candidates(url_str)? {
+            if let Some(base_url) = self.base_url_stop_crawling(&url).await {
+                return Ok(Response::Stopped(base_url.to_string()));
+            }
+        }
+
+        Err(CrawlerError::BaseUrlNotFound(url_str.to_string()).into())
+    }
+
+    async fn handle_command_list(&self) -> Result<Response> {
         let mut url_set = HashSet::new();
 
         // list through all BaseUrls
@@ -180,12 +651,136 @@ impl Crawler {
         }
 
         let url_parents = self.url_parents.read().await;
-        let mut indentation = String::new();
+        let url_nodes = self.url_nodes.read().await;
+        let mut lines = Vec::new();
+        list_children(
+            &url_parents,
+            &url_nodes,
+            &mut String::new(),
+            &url_set,
+            &mut HashSet::new(),
+            &mut lines,
+        );
+        for line in lines {
+            info!("{}", line);
+        }
 
-        print_children(&url_parents, &mut indentation, &url_set);
+        for (base_url, base_url_record) in base_urls.iter() {
+            let stats = base_url_record.stats();
+            let run_id = base_url_record.runs().last().map_or(0, |run| run.id);
+            info!(
+                "{} (run {}) fetched {} pages, {} of them not modified since the last fetch",
+                base_url, run_id, stats.pages_fetched, stats.not_modified
+            );
+        }
 
-        Ok(())
+        // list the links skipped by the include/exclude rules
+        for (base_url, base_url_record) in base_urls.iter() {
+            let excluded_urls = base_url_record.excluded_urls();
+            if excluded_urls.is_empty() {
+                continue;
+            }
+            info!("{} excluded {} links:", base_url, excluded_urls.len());
+            for (url, reason) in excluded_urls.iter() {
+                info!(" {} ({})", url, reason);
+            }
+        }
+
+        Ok(Response::Ok)
     }
+
+    // Compares two runs of a crawl, by default the latest one with the one before
+    async fn handle_command_diff(
+        &self,
+        url_str: &str,
+        run_a: Option<u32>,
+        run_b: Option<u32>,
+    ) -> Result<Response> {
+        let base_urls = self.base_urls.read().await;
+        let base_url = find_site(&base_urls, url_str)?;
+        let runs = base_urls[&base_url].runs();
+
+        let find_run = |id| {
+            runs.iter()
+                .find(|run| run.id == id)
+                .ok_or_else(|| CrawlerError::RunNotFound(base_url.to_string(), id))
+        };
+        let new_run = match run_b {
+            Some(id) => find_run(id)?,
+            None => runs
+                .last()
+                .ok_or_else(|| CrawlerError::NotEnoughRuns(base_url.to_string()))?,
+        };
+        let old_run = match run_a {
+            Some(id) => find_run(id)?,
+            None => runs
+                .iter()
+                .rev()
+                .find(|run| run.id < new_run.id)
+                .ok_or_else(|| CrawlerError::NotEnoughRuns(base_url.to_string()))?,
+        };
+
+        Ok(Response::Report(diff_report(&base_url, old_run, new_run)))
+    }
+
+    // Lists the broken links found by the latest run of a crawl
+    async fn handle_command_broken(&self, url_str: &str) -> Result<Response> {
+        let base_urls = self.base_urls.read().await;
+        let base_url = find_site(&base_urls, url_str)?;
+        let Some(run) = base_urls[&base_url].runs().last() else {
+            return Ok(Response::BrokenLinks(Vec::new()));
+        };
+
+        let url_links = self.url_links.read().await;
+        Ok(Response::BrokenLinks(broken_links(run, &url_links)))
+    }
+
+    // Checks the pages of the latest run of a crawl against SEO rules
+    async fn handle_command_audit(
+        &self,
+        url_str: &str,
+        options: &AuditOptions,
+    ) -> Result<Response> {
+        let base_urls = self.base_urls.read().await;
+        let base_url = find_site(&base_urls, url_str)?;
+        let Some(run) = base_urls[&base_url].runs().last() else {
+            return Ok(Response::AuditFindings(Vec::new()));
+        };
+
+        let url_nodes = self.url_nodes.read().await;
+        let url_links = self.url_links.read().await;
+        let findings = audit(run, &url_nodes, &url_links, options);
+        Ok(Response::AuditFindings(findings))
+    }
+
+    // Shows what was found about a single URL when it was last fetched
+    async fn handle_command_show(&self, url_str: &str) -> Result<Response> {
+        let url_nodes = self.url_nodes.read().await;
+        let report = seed_url_candidates(url_str)?
+            .into_iter()
+            .map(strip_url_to_domain_and_path)
+            .find_map(|url| url_nodes.get(&url).map(|node| node.report(&url)))
+            .ok_or_else(|| CrawlerError::UrlNotCrawled(url_str.to_string()))?;
+        Ok(Response::Report(report))
+    }
+}
+
+// The crawl the user means by `url_str`. Bare hosts match a crawl of either scheme.
+fn find_site(base_urls: &HashMap<Url, BaseUrl>, url_str: &str) -> Result<Url> {
+    let base_url = seed_url_candidates(url_str)?
+        .iter()
+        .find_map(|url| find_base_url(base_urls, url))
+        .ok_or_else(|| CrawlerError::BaseUrlNotFound(url_str.to_string()))?;
+    Ok(base_url)
+}
+
+// The most specific crawl containing the url
+fn find_base_url(base_urls: &HashMap<Url, BaseUrl>, url: &Url) -> Option<Url> {
+    base_urls
+        .keys()
+        .filter(|base_url| base_url.origin() == url.origin() && is_in_path_scope(base_url, url))
+        .max_by_key(|base_url| base_url.path().len())
+        .cloned()
 }
 
 fn strip_url_to_domain(mut url: Url) -> Url {
@@ -195,7 +790,16 @@ fn strip_url_to_domain(mut url: Url) -> Url {
     url
 }
 
-fn strip_url_to_domain_and_path(mut url: Url) -> Url {
+fn strip_url_to_site_root(url: Url, path_scope: bool) -> Url {
+    let path_opt = path_scope.then(|| path_scope_prefix(&url));
+    let mut url = strip_url_to_domain(url);
+    if let Some(path) = path_opt {
+        url.set_path(&path);
+    }
+    url
+}
+
+pub(crate) fn strip_url_to_domain_and_path(mut url: Url) -> Url {
     url.set_query(None);
     url.set_fragment(None);
 
@@ -208,17 +812,28 @@ fn strip_url_to_domain_and_path(mut url: Url) -> Url {
     url
 }
 
-fn print_children(
+// The URL a link to another site is recorded under
+pub(crate) fn strip_fragment(mut url: Url) -> Url {
+    url.set_fragment(None);
+    url
+}
+
+// Lists the tree under the children, one line per URL with children and one for the rest.
+// URLs already listed aren't listed under again, so a cycle in the tree can't recurse forever.
+fn list_children(
     url_parents: &HashMap<Url, HashSet<Url>>,
+    url_nodes: &HashMap<Url, Node>,
     indentation: &mut String,
     children: &HashSet<Url>,
+    visited: &mut HashSet<Url>,
+    lines: &mut Vec<String>,
 ) {
     let mut childed_urls = Vec::new();
     let mut childless_urls = Vec::new();
 
     for url in children.iter() {
         if let Some(children) = url_parents.get(url) {
-            if children.is_empty() {
+            if children.is_empty() || visited.contains(url) {
                 childless_urls.push(url);
             } else {
                 childed_urls.push((url, children));
@@ -229,27 +844,187 @@ fn print_children(
     }
 
     for (url, children) in childed_urls {
-        let url_str = if indentation.is_empty() {
-            // display full url if these are base urls
-            url.as_str()
-        } else {
-            // display only paths if the base url is known
-            url.path()
-        };
+        // listed by an earlier sibling's subtree
+        if !visited.insert(url.clone()) {
+            childless_urls.push(url);
+            continue;
+        }
+        let url_str = display_url(url_nodes, indentation, url);
 
-        info!("{}{}", indentation, url_str);
+        lines.push(format!("{}{}", indentation, url_str));
         indentation.push(' ');
-        print_children(url_parents, indentation, children);
+        list_children(
+            url_parents,
+            url_nodes,
+            indentation,
+            children,
+            visited,
+            lines,
+        );
         indentation.pop();
     }
 
-    let childless_urls: Vec<&str> = if indentation.is_empty() {
-        // display full url if these are childless base urls
-        childless_urls.iter().map(|url| url.as_str()).collect()
+    let childless_urls: Vec<String> = childless_urls
+        .iter()
+        .map(|url| display_url(url_nodes, indentation, url))
+        .collect();
+    let childless_urls = childless_urls.join(" ");
+    lines.push(format!("{}{}", indentation, childless_urls));
+}
+
+fn display_url(url_nodes: &HashMap<Url, Node>, indentation: &str, url: &Url) -> String {
+    let external = url_nodes.get(url).is_some_and(|node| node.external);
+    let url_str = if indentation.is_empty() || external {
+        // display full url if these are base urls, or on other sites
+        url.as_str()
     } else {
         // display only paths if the base url is known
-        childless_urls.iter().map(|url| url.path()).collect()
+        url.path()
     };
-    let childless_urls = childless_urls.join(" ");
-    info!("{}{}", indentation, childless_urls);
+
+    match url_nodes.get(url).and_then(|node| node.label()) {
+        Some(label) => format!("{}{}", url_str, label),
+        None => url_str.to_string(),
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use shared::CrawlOptions;
+
+    use crate::test_util::url;
+
+    async fn crawler(name: &str) -> Crawler {
+        let state_dir =
+            std::env::temp_dir().join(format!("crawler-tree-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&state_dir);
+        let (store, _) = Store::open(&state_dir).unwrap();
+        Crawler::new(store)
+    }
+
+    async fn start(crawler: &Crawler, start_url: &Url, path_scope: bool) -> Url {
+        let options = CrawlOptions {
+            path_scope,
+            ..CrawlOptions::default()
+        };
+        let filter = UrlFilter::new(&options.filters).unwrap();
+        let recrawl = Recrawl::new(&options.recrawl, Local::now()).unwrap();
+        crawler
+            .base_url_start_crawling(start_url, options, filter, recrawl)
+            .await
+    }
+
+    async fn list(crawler: &Crawler) -> Vec<String> {
+        let base_urls: HashSet<Url> = crawler.base_urls.read().await.keys().cloned().collect();
+        let mut lines = Vec::new();
+        list_children(
+            &*crawler.url_parents.read().await,
+            &*crawler.url_nodes.read().await,
+            &mut String::new(),
+            &base_urls,
+            &mut HashSet::new(),
+            &mut lines,
+        );
+        lines
+    }
+
+    #[tokio::test]
+    async fn test_self_referencing_asset() {
+        let crawler = crawler("self-asset").await;
+        let base_url = start(&crawler, &url("/"), false).await;
+        let page_url = url("/a");
+        crawler
+            .create_worker(&base_url, None, &page_url)
+            .await
+            .unwrap();
+        // `<link rel=canonical href=/a>` on `/a`, and `<form action=/>` on `/a`
+        for asset_url in [page_url.clone(), base_url.clone()] {
+            crawler
+                .record_asset(&base_url, &page_url, &asset_url, LinkKind::LinkHref)
+                .await
+                .unwrap();
+        }
+
+        assert!(crawler.url_parents.read().await[&page_url].is_empty());
+        assert_eq!(
+            list(&crawler).await,
+            vec!["https://example.com/", " /a", ""]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_path_scopes_linking_to_each_other() {
+        let crawler = crawler("path-scopes").await;
+        let docs_url = start(&crawler, &url("/docs/"), true).await;
+        let blog_url = start(&crawler, &url("/blog/"), true).await;
+        let (docs_page, blog_page) = (url("/docs/a"), url("/blog/b"));
+        crawler
+            .create_worker(&docs_url, None, &docs_page)
+            .await
+            .unwrap();
+        crawler
+            .create_worker(&blog_url, None, &blog_page)
+            .await
+            .unwrap();
+        crawler
+            .start_job(&docs_url, Some(&docs_page), &blog_page)
+            .await
+            .unwrap();
+        crawler
+            .start_job(&blog_url, Some(&blog_page), &docs_page)
+            .await
+            .unwrap();
+
+        let url_parents = crawler.url_parents.read().await;
+        assert!(url_parents[&docs_page].is_empty());
+        assert!(url_parents[&blog_page].is_empty());
+        drop(url_parents);
+        let lines = list(&crawler).await;
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|line| line.ends_with("/docs/a"))
+                .count(),
+            1
+        );
+        assert_eq!(
+            lines
+                .iter()
+                .filter(|line| line.ends_with("/blog/b"))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_list_children_with_a_cycle() {
+        let url_parents = HashMap::from([
+            (url("/"), HashSet::from([url("/a")])),
+            (url("/a"), HashSet::from([url("/")])),
+        ]);
+        let mut lines = Vec::new();
+        list_children(
+            &url_parents,
+            &HashMap::new(),
+            &mut String::new(),
+            &HashSet::from([url("/")]),
+            &mut HashSet::new(),
+            &mut lines,
+        );
+        assert_eq!(
+            lines,
+            vec![
+                "https://example.com/",
+                " /a",
+                // `/` is listed again, but not what's under it
+                "  /",
+                " ",
+                ""
+            ]
+        );
+    }
 }
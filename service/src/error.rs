@@ -18,22 +18,37 @@ pub enum CrawlerError {
     ParentUrlWorkerNotFound(String),
     #[error("Base URL not found: {0}")]
     BaseUrlNotFound(String),
+    #[error("Invalid URL pattern ({0}): {1}")]
+    InvalidUrlPattern(String, String),
+    #[error("Cannot reach start URL ({0}): {1}")]
+    UnreachableSeedUrl(String, String),
+    #[error("The parser threads have shut down")]
+    ParsePoolClosed,
+    #[error("Invalid recrawl policy ({0}): {1}")]
+    InvalidRecrawlPolicy(String, String),
+    #[error("Crawl of {0} has no run {1}")]
+    RunNotFound(String, u32),
+    #[error("Crawl of {0} has fewer than two runs to compare")]
+    NotEnoughRuns(String),
+    #[error("URL has not been crawled: {0}")]
+    UrlNotCrawled(String),
+    #[error("Too many redirects fetching {0}")]
+    TooManyRedirects(String),
+    #[error("Cannot load the saved state in {0} ({1}); it may have been written by another version of the service. Move it aside to start afresh")]
+    IncompatibleState(String, String),
 }
 
 impl CrawlerError {
     pub fn should_display_error(&self) -> bool {
-        match self {
+        !matches!(
+            self,
             CrawlerError::LinkUrlDoesNotMatchBaseUrl(_, _)
-            | CrawlerError::BaseUrlHasStoppedCrawling(_, _) => false,
-            _ => true,
-        }
+                | CrawlerError::BaseUrlHasStoppedCrawling(_, _)
+        )
     }
 
     pub fn should_display_backtrace(&self) -> bool {
-        match self {
-            CrawlerError::BaseUrlHasStoppedCrawling(_, _) => false,
-            _ => true,
-        }
+        !matches!(self, CrawlerError::BaseUrlHasStoppedCrawling(_, _))
     }
 
     pub fn print(self) {
@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use reqwest::{Client, Method, StatusCode};
+use tokio::sync::{Mutex, Semaphore};
+use url::Url;
+
+use shared::ExternalLinkOptions;
+
+use crate::run::PageStatus;
+
+// How many external links are checked at once, across all crawls
+const MAX_CONCURRENT_CHECKS: usize = 8;
+// How long a link's status is reused for other links to the same URL
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Checks links leaving the crawled sites, without downloading or following them. Checks are
+// kept apart from the crawl: they have their own client and timeouts, a limit on how many run
+// at once, a delay between requests to the same host, and a cache of recent results.
+#[derive(Clone)]
+pub(crate) struct ExternalChecker {
+    client: Client,
+    permits: Arc<Semaphore>,
+    cache: Arc<Mutex<HashMap<Url, CachedStatus>>>,
+    // when the next request to each host may be sent
+    host_slots: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+#[derive(Clone)]
+struct CachedStatus {
+    status: PageStatus,
+    checked_at: Instant,
+}
+
+impl ExternalChecker {
+    pub(crate) fn new() -> Self {
+        Self {
+            client: Client::new(),
+            permits: Arc::new(Semaphore::new(MAX_CONCURRENT_CHECKS)),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            host_slots: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) async fn check(&self, url: &Url, options: &ExternalLinkOptions) -> PageStatus {
+        if let Some(cached) = self.cache.lock().await.get(url) {
+            if cached.checked_at.elapsed() < CACHE_TTL {
+                return cached.status.clone();
+            }
+        }
+
+        let slot = {
+            let mut host_slots = self.host_slots.lock().await;
+            let host = url.host_str().unwrap_or_default();
+            let delay = Duration::from_secs(options.host_delay_secs);
+            reserve_slot(&mut host_slots, host, Instant::now(), delay)
+        };
+        tokio::time::sleep_until(slot.into()).await;
+        // the semaphore is never closed
+        let _permit = self.permits.acquire().await.ok();
+
+        let timeout = Duration::from_secs(options.timeout_secs);
+        let mut result = self.request(Method::HEAD, url, timeout).await;
+        // some servers refuse HEAD requests, but answer the same URL fine
+        let head_refused = result.as_ref().is_ok_and(|status| {
+            [StatusCode::METHOD_NOT_ALLOWED, StatusCode::NOT_IMPLEMENTED].contains(status)
+        });
+        if head_refused {
+            result = self.request(Method::GET, url, timeout).await;
+        }
+        let status = match result {
+            Ok(status) => PageStatus::Http(status.as_u16()),
+            Err(err) => PageStatus::from_error(&anyhow::Error::from(err)),
+        };
+
+        let cached = CachedStatus {
+            status: status.clone(),
+            checked_at: Instant::now(),
+        };
+        self.cache.lock().await.insert(url.clone(), cached);
+        status
+    }
+
+    // Sends the request, without reading the body of the response
+    async fn request(
+        &self,
+        method: Method,
+        url: &Url,
+        timeout: Duration,
+    ) -> Result<StatusCode, reqwest::Error> {
+        let request = self.client.request(method, url.clone()).timeout(timeout);
+        let response = request.send().await?;
+        Ok(response.status())
+    }
+}
+
+// The earliest a request to the host may be sent, at least `delay` after the one before it,
+// which is reserved for the caller
+fn reserve_slot(
+    host_slots: &mut HashMap<String, Instant>,
+    host: &str,
+    now: Instant,
+    delay: Duration,
+) -> Instant {
+    let slot = host_slots.get(host).map_or(now, |&next| next.max(now));
+    host_slots.insert(host.to_string(), slot + delay);
+    slot
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_slot() {
+        let mut host_slots = HashMap::new();
+        let now = Instant::now();
+        let delay = Duration::from_secs(1);
+
+        assert_eq!(reserve_slot(&mut host_slots, "a.com", now, delay), now);
+        // requests to the same host queue up one delay apart
+        assert_eq!(
+            reserve_slot(&mut host_slots, "a.com", now, delay),
+            now + delay
+        );
+        assert_eq!(
+            reserve_slot(&mut host_slots, "a.com", now, delay),
+            now + delay * 2
+        );
+        // other hosts don't wait for them
+        assert_eq!(reserve_slot(&mut host_slots, "b.com", now, delay), now);
+        // and once the host has been quiet long enough, there's no wait
+        let later = now + delay * 10;
+        assert_eq!(reserve_slot(&mut host_slots, "a.com", later, delay), later);
+    }
+}
@@ -0,0 +1,405 @@
+use anyhow::Result;
+use bytes::Bytes;
+use encoding_rs::Encoding;
+use log::warn;
+use reqwest::{
+    header::{self, HeaderMap},
+    Client, Method, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use shared::FetchOptions;
+
+use crate::error::CrawlerError;
+
+// Redirects beyond this many in a row are given up on, as they're most likely a loop
+const MAX_REDIRECTS: usize = 10;
+
+// Extensions that usually point at downloads rather than pages
+const SUSPICIOUS_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avi", "bin", "bmp", "css", "csv", "dmg", "doc", "docx", "eot", "exe", "flac",
+    "gif", "gz", "ico", "iso", "jpeg", "jpg", "js", "json", "m4a", "mkv", "mov", "mp3", "mp4",
+    "msi", "ogg", "pdf", "png", "ppt", "pptx", "rar", "svg", "tar", "tgz", "tif", "tiff", "ttf",
+    "wav", "webm", "webp", "woff", "woff2", "xls", "xlsx", "xml", "zip",
+];
+
+pub(crate) struct FetchedPage {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    // Each URL the request was redirected to, in order, the last one having answered
+    pub(crate) redirects: Vec<Url>,
+    pub(crate) outcome: FetchOutcome,
+}
+
+pub(crate) enum FetchOutcome {
+    // An HTML document, whose body is streamed as it downloads
+    Html(Box<HtmlBody>),
+    // Anything that isn't HTML, with its MIME type
+    NotHtml(String),
+    // The page hasn't changed since the response the validators came from
+    NotModified,
+}
+
+// What a response said about its version of the page, sent back on the next fetch so the
+// server can answer `304 Not Modified` instead of sending it again
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Self {
+        let value = |name| {
+            let value = headers.get(name)?;
+            value.to_str().ok().map(|value| value.to_string())
+        };
+        Self {
+            etag: value(header::ETAG),
+            last_modified: value(header::LAST_MODIFIED),
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
+
+// Fetches the URL, returning the response status and headers along with the outcome. With validators,
+// the page is only sent again if it changed.
+pub(crate) async fn fetch_page(
+    client: &Client,
+    url: &Url,
+    options: &FetchOptions,
+    validators: &Validators,
+) -> Result<FetchedPage> {
+    let fetched = |response: &Response, redirects, outcome| FetchedPage {
+        status: response.status(),
+        headers: response.headers().clone(),
+        redirects,
+        outcome,
+    };
+
+    if options.head_suspicious && has_suspicious_extension(url) {
+        let (response, redirects) =
+            send_following_redirects(client, Method::HEAD, url, &HeaderMap::new()).await?;
+        if response.status().is_success() {
+            if let Some(outcome) = check_headers(&response) {
+                return Ok(fetched(&response, redirects, outcome));
+            }
+        }
+    }
+
+    let mut request_headers = HeaderMap::new();
+    if !validators.is_empty() {
+        let etag = validators
+            .etag
+            .as_deref()
+            .and_then(|etag| etag.parse().ok());
+        if let Some(etag) = etag {
+            request_headers.insert(header::IF_NONE_MATCH, etag);
+        }
+        let last_modified = validators.last_modified.as_deref();
+        if let Some(last_modified) = last_modified.and_then(|value| value.parse().ok()) {
+            request_headers.insert(header::IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let (response, redirects) =
+        send_following_redirects(client, Method::GET, url, &request_headers).await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(fetched(&response, redirects, FetchOutcome::NotModified));
+    }
+    if let Some(outcome) = check_headers(&response) {
+        return Ok(fetched(&response, redirects, outcome));
+    }
+
+    let charset = content_type(&response).and_then(|content_type| charset(&content_type));
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = Box::new(HtmlBody {
+        response,
+        url: url.clone(),
+        encoding: charset.and_then(|label| Encoding::for_label(label.as_bytes())),
+        remaining_bytes: options.max_body_bytes,
+        truncated: false,
+    });
+    Ok(FetchedPage {
+        status,
+        headers,
+        redirects,
+        outcome: FetchOutcome::Html(body),
+    })
+}
+
+// Sends the request, following redirects here rather than in the client so the chain can be
+// reported. Returns the final response along with each URL redirected to.
+pub(crate) async fn send_following_redirects(
+    client: &Client,
+    mut method: Method,
+    url: &Url,
+    headers: &HeaderMap,
+) -> Result<(Response, Vec<Url>)> {
+    let mut redirects = Vec::new();
+    let mut request_url = url.clone();
+    let mut headers = headers.clone();
+    loop {
+        let request = client.request(method.clone(), request_url.clone());
+        let response = request.headers(headers.clone()).send().await?;
+        let Some(location) = redirect_location(&response) else {
+            return Ok((response, redirects));
+        };
+        if redirects.len() == MAX_REDIRECTS {
+            return Err(CrawlerError::TooManyRedirects(url.to_string()).into());
+        }
+        // a `303 See Other` is answered with a GET, whatever the request was
+        if response.status() == StatusCode::SEE_OTHER && method != Method::HEAD {
+            method = Method::GET;
+        }
+        request_url = request_url.join(&location)?;
+        // validators only mean something to the server they came from
+        if request_url.origin() != url.origin() {
+            headers.remove(header::IF_NONE_MATCH);
+            headers.remove(header::IF_MODIFIED_SINCE);
+        }
+        redirects.push(request_url.clone());
+    }
+}
+
+// Where the response redirects to, if it's a redirect with a `Location`
+fn redirect_location(response: &Response) -> Option<String> {
+    let redirects = [
+        StatusCode::MOVED_PERMANENTLY,
+        StatusCode::FOUND,
+        StatusCode::SEE_OTHER,
+        StatusCode::TEMPORARY_REDIRECT,
+        StatusCode::PERMANENT_REDIRECT,
+    ];
+    if !redirects.contains(&response.status()) {
+        return None;
+    }
+    let location = response.headers().get(header::LOCATION)?;
+    location.to_str().ok().map(|location| location.to_string())
+}
+
+pub(crate) struct HtmlBody {
+    response: Response,
+    url: Url,
+    encoding: Option<&'static Encoding>,
+    remaining_bytes: u64,
+    // more of the body arrived than the size limit allows
+    truncated: bool,
+}
+
+impl HtmlBody {
+    // The encoding declared by the `Content-Type` header, if it names a known one
+    pub(crate) fn encoding(&self) -> Option<&'static Encoding> {
+        self.encoding
+    }
+
+    // The next chunk of the body as it arrives, stopping once the size limit is reached
+    pub(crate) async fn chunk(&mut self) -> Result<Option<Bytes>> {
+        if self.truncated {
+            return Ok(None);
+        }
+        let Some(mut chunk) = self.response.chunk().await? else {
+            return Ok(None);
+        };
+        if chunk.len() as u64 > self.remaining_bytes {
+            chunk.truncate(self.remaining_bytes as usize);
+            self.truncated = true;
+            warn!("Truncated {} at the size limit", self.url);
+        }
+        self.remaining_bytes -= chunk.len() as u64;
+        Ok((!chunk.is_empty()).then_some(chunk))
+    }
+
+    // Whether the body was cut short at the size limit, so only its start was read
+    pub(crate) fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+// Decides from the headers alone whether the body is worth downloading. Bodies over the size
+// limit are still read up to it, whether or not they declared their length, and marked as
+// truncated.
+fn check_headers(response: &Response) -> Option<FetchOutcome> {
+    let mime_type = mime_type(&content_type(response)?);
+    (!is_html_mime_type(&mime_type)).then_some(FetchOutcome::NotHtml(mime_type))
+}
+
+fn content_type(response: &Response) -> Option<String> {
+    let value = response.headers().get(header::CONTENT_TYPE)?;
+    value.to_str().ok().map(|value| value.to_string())
+}
+
+fn mime_type(content_type: &str) -> String {
+    let mime_type = content_type.split(';').next().unwrap_or("");
+    mime_type.trim().to_ascii_lowercase()
+}
+
+fn charset(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("charset") {
+            Some(value.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+fn is_html_mime_type(mime_type: &str) -> bool {
+    mime_type == "text/html" || mime_type == "application/xhtml+xml"
+}
+
+fn has_suspicious_extension(url: &Url) -> bool {
+    let last_segment = url.path().rsplit('/').next().unwrap_or("");
+    match last_segment.rsplit_once('.') {
+        Some((_, extension)) => {
+            let extension = extension.to_ascii_lowercase();
+            SUSPICIOUS_EXTENSIONS.contains(&extension.as_str())
+        }
+        None => false,
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_mime_type_and_charset() {
+        let content_type = "Text/HTML; charset=\"ISO-8859-1\"";
+        assert_eq!(mime_type(content_type), "text/html");
+        assert_eq!(charset(content_type), Some("ISO-8859-1".to_string()));
+        assert_eq!(charset("application/pdf"), None);
+        assert!(is_html_mime_type("application/xhtml+xml"));
+        assert!(!is_html_mime_type("image/png"));
+    }
+
+    #[test]
+    fn test_validators_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert!(Validators::from_headers(&headers).is_empty());
+        headers.insert(header::ETAG, "\"abc\"".parse().unwrap());
+        headers.insert(
+            header::LAST_MODIFIED,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap(),
+        );
+        let validators = Validators::from_headers(&headers);
+        assert_eq!(validators.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(
+            validators.last_modified.as_deref(),
+            Some("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+    }
+
+    // Answers a single request on a local port with the response
+    async fn serve(response: String) -> Url {
+        serve_recording(response).await.0
+    }
+
+    // The same, also passing on the request's head
+    async fn serve_recording(response: String) -> (Url, tokio::sync::oneshot::Receiver<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 1024];
+            let length = stream.read(&mut request).await.unwrap_or(0);
+            let _ = sender.send(String::from_utf8_lossy(&request[..length]).into_owned());
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        (
+            Url::parse(&format!("http://{}/", address)).unwrap(),
+            receiver,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_oversized_body_is_truncated() {
+        let options = FetchOptions {
+            max_body_bytes: 10,
+            ..FetchOptions::default()
+        };
+        let with_length = "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\
+                           Content-Length: 26\r\n\r\nabcdefghijklmnopqrstuvwxyz";
+        let chunked = "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\
+                       Transfer-Encoding: chunked\r\n\r\n\
+                       1a\r\nabcdefghijklmnopqrstuvwxyz\r\n0\r\n\r\n";
+        // whether or not the length is declared up front
+        for response in [with_length, chunked] {
+            let url = serve(response.to_string()).await;
+            let fetched = fetch_page(&Client::new(), &url, &options, &Validators::default())
+                .await
+                .unwrap();
+            let FetchOutcome::Html(mut body) = fetched.outcome else {
+                panic!("{} wasn't read as HTML", url);
+            };
+            let mut bytes = Vec::new();
+            while let Some(chunk) = body.chunk().await.unwrap() {
+                bytes.extend_from_slice(&chunk);
+            }
+            assert_eq!(bytes, b"abcdefghij");
+            assert!(body.is_truncated());
+        }
+
+        // a body that's exactly the limit is whole
+        let url = serve(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\
+             Content-Length: 10\r\n\r\nabcdefghij"
+                .to_string(),
+        )
+        .await;
+        let fetched = fetch_page(&Client::new(), &url, &options, &Validators::default())
+            .await
+            .unwrap();
+        let FetchOutcome::Html(mut body) = fetched.outcome else {
+            panic!("{} wasn't read as HTML", url);
+        };
+        while body.chunk().await.unwrap().is_some() {}
+        assert!(!body.is_truncated());
+    }
+
+    #[tokio::test]
+    async fn test_validators_stay_with_their_origin() {
+        let ok = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n".to_string();
+        let (other_url, other_request) = serve_recording(ok).await;
+        let redirect = format!(
+            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n",
+            other_url
+        );
+        let (url, request) = serve_recording(redirect).await;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::IF_NONE_MATCH, "\"abc\"".parse().unwrap());
+        // as the crawler's client, which leaves redirects to the fetcher
+        let client = Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let (_, redirects) = send_following_redirects(&client, Method::GET, &url, &headers)
+            .await
+            .unwrap();
+        assert_eq!(redirects, vec![other_url]);
+        let has_validator = |request: String| request.to_lowercase().contains("if-none-match");
+        assert!(has_validator(request.await.unwrap()));
+        assert!(!has_validator(other_request.await.unwrap()));
+    }
+
+    #[test]
+    fn test_suspicious_extension() {
+        let suspicious = |url: &str| has_suspicious_extension(&Url::parse(url).unwrap());
+        assert!(suspicious("https://example.com/files/report.PDF"));
+        assert!(suspicious("https://example.com/logo.png"));
+        assert!(!suspicious("https://example.com/about.html"));
+        assert!(!suspicious("https://example.com/v1.2/docs"));
+        assert!(!suspicious("https://example.com/"));
+    }
+}
@@ -0,0 +1,162 @@
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use url::Url;
+
+use shared::{UrlFilters, UrlPattern};
+
+use crate::error::CrawlerError;
+
+pub(crate) struct UrlFilter {
+    include: Vec<UrlMatcher>,
+    exclude: Vec<UrlMatcher>,
+}
+
+impl UrlFilter {
+    pub(crate) fn new(filters: &UrlFilters) -> Result<Self, CrawlerError> {
+        Ok(Self {
+            include: compile_patterns(&filters.include)?,
+            exclude: compile_patterns(&filters.exclude)?,
+        })
+    }
+
+    // Returns why the URL should not be crawled, or None if it passes all rules
+    pub(crate) fn exclusion_reason(&self, url: &Url) -> Option<String> {
+        if let Some(matcher) = self.exclude.iter().find(|matcher| matcher.is_match(url)) {
+            return Some(format!("matches exclude pattern {}", matcher));
+        }
+        if !self.include.is_empty() && !self.include.iter().any(|matcher| matcher.is_match(url)) {
+            return Some("does not match any include pattern".to_string());
+        }
+        None
+    }
+}
+
+fn compile_patterns(patterns: &[UrlPattern]) -> Result<Vec<UrlMatcher>, CrawlerError> {
+    patterns.iter().map(UrlMatcher::new).collect()
+}
+
+enum UrlMatcher {
+    PathGlob(GlobMatcher),
+    UrlGlob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl UrlMatcher {
+    fn new(pattern: &UrlPattern) -> Result<Self, CrawlerError> {
+        match pattern {
+            UrlPattern::Glob(glob) => {
+                let matcher = Glob::new(glob)
+                    .map_err(|err| CrawlerError::InvalidUrlPattern(glob.clone(), err.to_string()))?
+                    .compile_matcher();
+                if glob.starts_with('/') {
+                    Ok(Self::PathGlob(matcher))
+                } else {
+                    Ok(Self::UrlGlob(matcher))
+                }
+            }
+            UrlPattern::Regex(regex) => {
+                let regex = Regex::new(regex).map_err(|err| {
+                    CrawlerError::InvalidUrlPattern(regex.clone(), err.to_string())
+                })?;
+                Ok(Self::Regex(regex))
+            }
+        }
+    }
+
+    fn is_match(&self, url: &Url) -> bool {
+        match self {
+            Self::PathGlob(matcher) => matcher.is_match(url.path()),
+            Self::UrlGlob(matcher) => matcher.is_match(url.as_str()),
+            Self::Regex(regex) => regex.is_match(url.as_str()),
+        }
+    }
+}
+
+impl std::fmt::Display for UrlMatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PathGlob(matcher) | Self::UrlGlob(matcher) => {
+                write!(f, "`{}`", matcher.glob())
+            }
+            Self::Regex(regex) => write!(f, "/{}/", regex),
+        }
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob(pattern: &str) -> UrlPattern {
+        UrlPattern::Glob(pattern.to_string())
+    }
+
+    fn reason(filter: &UrlFilter, url: &str) -> Option<String> {
+        filter.exclusion_reason(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn test_exclude_globs() {
+        let filter = UrlFilter::new(&UrlFilters {
+            include: Vec::new(),
+            exclude: vec![
+                glob("/logout"),
+                glob("/cart"),
+                glob("*.pdf"),
+                glob("/wp-admin/*"),
+            ],
+        })
+        .unwrap();
+
+        assert_eq!(reason(&filter, "https://example.com/about"), None);
+        assert_eq!(reason(&filter, "https://example.com/logout/now"), None);
+        assert_eq!(
+            reason(&filter, "https://example.com/logout"),
+            Some("matches exclude pattern `/logout`".to_string())
+        );
+        assert_eq!(
+            reason(&filter, "https://example.com/files/report.pdf"),
+            Some("matches exclude pattern `*.pdf`".to_string())
+        );
+        assert_eq!(
+            reason(&filter, "https://example.com/wp-admin/users/edit"),
+            Some("matches exclude pattern `/wp-admin/*`".to_string())
+        );
+        // links are matched with their trailing slash
+        assert_eq!(
+            reason(&filter, "https://example.com/wp-admin/"),
+            Some("matches exclude pattern `/wp-admin/*`".to_string())
+        );
+        assert_eq!(reason(&filter, "https://example.com/wp-admin"), None);
+    }
+
+    #[test]
+    fn test_include_and_exclude() {
+        let filter = UrlFilter::new(&UrlFilters {
+            include: vec![UrlPattern::Regex("/docs/".to_string())],
+            exclude: vec![glob("/docs/internal/*")],
+        })
+        .unwrap();
+
+        assert_eq!(reason(&filter, "https://example.com/docs/intro"), None);
+        assert_eq!(
+            reason(&filter, "https://example.com/blog"),
+            Some("does not match any include pattern".to_string())
+        );
+        assert_eq!(
+            reason(&filter, "https://example.com/docs/internal/plan"),
+            Some("matches exclude pattern `/docs/internal/*`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let result = UrlFilter::new(&UrlFilters {
+            include: vec![UrlPattern::Regex("(".to_string())],
+            exclude: Vec::new(),
+        });
+        assert!(result.is_err());
+    }
+}
@@ -1,40 +1,77 @@
+mod audit;
 mod base_url;
+mod charset;
 mod crawler;
 mod error;
+mod external;
+mod fetcher;
+mod filter;
+mod node;
+mod parse_pool;
 mod parser;
+mod recrawl;
+mod robots;
+mod run;
+mod scope;
+mod seed_url;
+mod sitemap;
+mod store;
+#[cfg(test)]
+mod test_util;
 mod url_worker;
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, path::PathBuf};
 
 use anyhow::Result;
-use bincode;
+use clap::Parser;
 use log::info;
 use tokio::{
-    io::AsyncReadExt,
+    io::{AsyncReadExt, AsyncWriteExt},
     net::TcpListener,
     net::TcpStream,
     sync::{
         mpsc,
         mpsc::{Receiver, Sender},
+        oneshot,
     },
 };
 
-use shared::Command;
+use shared::{Command, Response};
 
 use crate::{
     crawler::Crawler,
     error::{print_error_and_backtrace, CrawlerError},
+    store::Store,
 };
 
+#[derive(Parser)]
+#[command(version = "1.0", about = "Web Crawler Daemon")]
+struct Args {
+    /// Directory the crawl state is kept in, so crawls survive a restart
+    #[arg(long, default_value = "crawler-state")]
+    state_dir: PathBuf,
+}
+
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
+
     // Initialize logger
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
+    // Load the state saved by the previous run
+    let (store, saved_state) = match Store::open(&args.state_dir) {
+        Ok(opened) => opened,
+        Err(err) => {
+            print_error_and_backtrace(err);
+            return;
+        }
+    };
+
     info!("Starting Web Crawler Daemon on 127.0.0.1:8080");
 
     // Channel to receive commands from client
-    let (command_sender, command_receiver) = mpsc::channel::<Command>(32);
+    let (command_sender, command_receiver) = mpsc::channel::<CommandRequest>(32);
 
     // Setup the request reader loop
     tokio::spawn(async move {
@@ -44,7 +81,8 @@ async fn main() {
     });
 
     // Setup the command receiver loop
-    let crawler = Crawler::new();
+    let crawler = Crawler::restore(store, saved_state).await;
+    crawler.start_recrawl_scheduler();
     tokio::spawn(async move {
         command_receiver_loop(crawler, command_receiver).await;
     });
@@ -54,7 +92,10 @@ async fn main() {
     info!("Shutting down...");
 }
 
-async fn request_reader_loop(command_sender: Sender<Command>) -> Result<()> {
+// A command along with the channel its response should be sent back on
+type CommandRequest = (Command, oneshot::Sender<Response>);
+
+async fn request_reader_loop(command_sender: Sender<CommandRequest>) -> Result<()> {
     let listener = TcpListener::bind("127.0.0.1:8080").await?;
     loop {
         match request_accept(&listener).await {
@@ -79,34 +120,49 @@ async fn request_accept(listener: &TcpListener) -> Result<(TcpStream, SocketAddr
 async fn request_read(
     mut socket: TcpStream,
     addr: SocketAddr,
-    sender_clone: Sender<Command>,
+    sender_clone: Sender<CommandRequest>,
 ) -> Result<()> {
-    let mut buffer = [0; 1024];
-    let bytes_number = socket.read(&mut buffer).await?;
+    // The client shuts down its side of the connection once the command is written
+    let mut buffer = Vec::new();
+    socket.read_to_end(&mut buffer).await?;
     info!("Received TCP message from: {:?}", addr);
 
     // Deserialize command from received bytes using bincode
-    let command = bincode::deserialize::<Command>(&buffer[..bytes_number])?;
+    let command = bincode::deserialize::<Command>(&buffer)?;
 
     // Send command to the command handler
     // info!("Sending to command channel: {:?}", command);
-    sender_clone.send(command).await?;
+    let (response_sender, response_receiver) = oneshot::channel();
+    sender_clone.send((command, response_sender)).await?;
+
+    // Send the response back to the client
+    let response = response_receiver.await?;
+    let encoded: Vec<u8> = bincode::serialize(&response)?;
+    socket.write_all(&encoded).await?;
+    socket.shutdown().await?;
 
     Ok(())
 }
 
-async fn command_receiver_loop(crawler: Crawler, mut cmd_receiver: Receiver<Command>) {
+async fn command_receiver_loop(crawler: Crawler, mut cmd_receiver: Receiver<CommandRequest>) {
     loop {
         match cmd_receiver.recv().await {
-            Some(command) => {
+            Some((command, response_sender)) => {
                 info!("Received Command: {:?}", command);
 
                 // Spawn a new task to handle the command
                 let crawler_clone = crawler.clone();
                 tokio::spawn(async move {
-                    if let Err(command_error) = crawler_clone.handle_command(command).await {
-                        print_error_and_backtrace(command_error);
-                    }
+                    let response = match crawler_clone.handle_command(command).await {
+                        Ok(response) => response,
+                        Err(command_error) => {
+                            let message = format!("{:#}", command_error);
+                            print_error_and_backtrace(command_error);
+                            Response::Error(message)
+                        }
+                    };
+                    // the client may have disconnected already
+                    let _ = response_sender.send(response);
                 });
             }
             None => {
@@ -0,0 +1,172 @@
+use std::fmt::Write;
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use shared::LinkKind;
+
+use crate::{parser::PageMetadata, run::PageStatus};
+
+// What the crawler learned about a URL when fetching it
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Node {
+    pub(crate) mime_type: Option<String>,
+    // Set for resources referenced by a page (images, scripts, ...) that aren't followed
+    pub(crate) asset_kind: Option<LinkKind>,
+    // The page asked not to be indexed, through a meta tag or the `X-Robots-Tag` header
+    pub(crate) noindex: bool,
+    // The page asked for none of its links to be followed, the same ways
+    pub(crate) nofollow: bool,
+    // Set for links leaving the crawled site, which are checked rather than crawled
+    pub(crate) external: bool,
+    // What the last check of an external link found
+    pub(crate) link_status: Option<PageStatus>,
+    // What the page said about itself, for HTML pages
+    pub(crate) metadata: Option<PageMetadata>,
+    // Each URL the last fetch was redirected to, in order
+    pub(crate) redirects: Vec<Url>,
+    // Only the start of the last response was read, as the rest was over the size limit
+    pub(crate) truncated: bool,
+}
+
+impl Node {
+    // A short label shown next to the URL when listing, for nodes that weren't parsed as pages
+    pub(crate) fn label(&self) -> Option<String> {
+        let mut labels = Vec::new();
+        match (&self.mime_type, self.asset_kind) {
+            (Some(mime_type), _) if mime_type != "text/html" => labels.push(mime_type.as_str()),
+            (None, Some(asset_kind)) => labels.push(asset_kind.name()),
+            _ => {}
+        }
+        if self.noindex {
+            labels.push("noindex");
+        }
+        if self.truncated {
+            labels.push("truncated");
+        }
+        let link_status = self.link_status.as_ref().map(|status| status.to_string());
+        if self.external {
+            labels.push("external");
+            labels.extend(link_status.as_deref());
+        }
+
+        if labels.is_empty() {
+            None
+        } else {
+            Some(format!("[{}]", labels.join(", ")))
+        }
+    }
+
+    // Everything known about the URL, for `show`
+    pub(crate) fn report(&self, url: &Url) -> String {
+        let mut report = url.to_string();
+        if let Some(label) = self.label() {
+            let _ = write!(report, " {}", label);
+        }
+        report.push('\n');
+        for redirect in &self.redirects {
+            let _ = writeln!(report, "  Redirected to {}", redirect);
+        }
+
+        let Some(metadata) = &self.metadata else {
+            report.push_str("  Not parsed as an HTML page\n");
+            return report;
+        };
+        let fields = [
+            ("Title", metadata.title.as_deref()),
+            ("Description", metadata.description.as_deref()),
+            ("Canonical", metadata.canonical.as_deref()),
+            ("Language", metadata.lang.as_deref()),
+        ];
+        for (name, value) in fields {
+            let _ = writeln!(report, "  {}: {}", name, value.unwrap_or("(none)"));
+        }
+        let _ = writeln!(report, "  Words: {}", metadata.word_count);
+        let _ = writeln!(report, "  H1 ({}):", metadata.h1s.len());
+        for h1 in &metadata.h1s {
+            let _ = writeln!(report, "    {}", h1);
+        }
+        let _ = writeln!(report, "  Open Graph ({}):", metadata.open_graph.len());
+        for (property, content) in &metadata.open_graph {
+            let _ = writeln!(report, "    {}: {}", property, content);
+        }
+        let _ = writeln!(report, "  JSON-LD ({}):", metadata.json_ld.len());
+        for block in &metadata.json_ld {
+            for line in block.lines() {
+                let _ = writeln!(report, "    {}", line);
+            }
+        }
+        let images_without_alt = &metadata.images_without_alt;
+        let _ = writeln!(
+            report,
+            "  Images without alt ({}):",
+            images_without_alt.len()
+        );
+        for src in images_without_alt {
+            let _ = writeln!(report, "    {}", src);
+        }
+        report
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let url = Url::parse("https://example.com/about").unwrap();
+        let mut node = Node {
+            mime_type: Some("text/html".to_string()),
+            noindex: true,
+            redirects: vec![Url::parse("https://example.com/about-us").unwrap()],
+            ..Node::default()
+        };
+        assert_eq!(
+            node.report(&url),
+            "https://example.com/about [noindex]\n\
+             \x20 Redirected to https://example.com/about-us\n\
+             \x20 Not parsed as an HTML page\n"
+        );
+
+        node.metadata = Some(PageMetadata {
+            title: Some("About us".to_string()),
+            canonical: Some("https://example.com/about".to_string()),
+            h1s: vec!["About".to_string(), "Team".to_string()],
+            word_count: 120,
+            open_graph: vec![("og:title".to_string(), "About".to_string())],
+            json_ld: vec!["{\n  \"@type\": \"Organization\"\n}".to_string()],
+            images_without_alt: vec!["/team.jpg".to_string()],
+            ..PageMetadata::default()
+        });
+        let report = node.report(&url);
+        let lines: Vec<&str> = report.lines().skip(2).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "  Title: About us",
+                "  Description: (none)",
+                "  Canonical: https://example.com/about",
+                "  Language: (none)",
+                "  Words: 120",
+                "  H1 (2):",
+                "    About",
+                "    Team",
+                "  Open Graph (1):",
+                "    og:title: About",
+                "  JSON-LD (1):",
+                "    {",
+                "      \"@type\": \"Organization\"",
+                "    }",
+                "  Images without alt (1):",
+                "    /team.jpg",
+            ]
+        );
+
+        node.truncated = true;
+        let report = node.report(&url);
+        assert!(report.starts_with("https://example.com/about [noindex, truncated]\n"));
+    }
+}
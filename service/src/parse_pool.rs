@@ -0,0 +1,255 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::{
+    runtime::Handle,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        oneshot,
+    },
+};
+
+use crate::error::CrawlerError;
+
+// How many messages may wait for each parser thread. Once a thread falls behind, downloads
+// wait for room instead of piling chunks up in memory.
+const PARSE_QUEUE_CAPACITY: usize = 64;
+
+// Consumes a document a chunk at a time. It's created on the parser thread it runs on and
+// never leaves it, so it doesn't have to be `Send` (the HTML tokenizer isn't).
+pub(crate) trait ChunkConsumer {
+    fn push(&mut self, chunk: &[u8]);
+    fn finish(self: Box<Self>);
+}
+
+type ConsumerFactory = Box<dyn FnOnce() -> Box<dyn ChunkConsumer> + Send>;
+
+enum ParseMessage {
+    Start(u64, ConsumerFactory),
+    Chunk(u64, Bytes),
+    // signalled once the consumer has handled the end of the document
+    Finish(u64, oneshot::Sender<()>),
+}
+
+// Dedicated threads for tokenizing pages, so CPU-bound parsing never blocks the async workers
+// that handle fetches and commands. Each document sticks to one thread, which interleaves the
+// chunks of every document assigned to it.
+#[derive(Clone)]
+pub(crate) struct ParsePool {
+    senders: Arc<Vec<Sender<ParseMessage>>>,
+    next_stream_id: Arc<AtomicU64>,
+}
+
+impl ParsePool {
+    // Must be called from within the runtime, which the consumers can spawn tasks onto
+    pub(crate) fn new() -> Self {
+        let thread_count = thread::available_parallelism().map_or(1, |count| count.get());
+        Self::with_threads(thread_count, PARSE_QUEUE_CAPACITY)
+    }
+
+    fn with_threads(thread_count: usize, queue_capacity: usize) -> Self {
+        let runtime = Handle::current();
+        let mut senders = Vec::new();
+
+        for index in 0..thread_count {
+            let (sender, receiver) = mpsc::channel(queue_capacity);
+            let runtime = runtime.clone();
+            thread::Builder::new()
+                .name(format!("parser-{}", index))
+                .spawn(move || Self::run_thread(receiver, &runtime))
+                .expect("failed to spawn parser thread");
+            senders.push(sender);
+        }
+
+        Self {
+            senders: Arc::new(senders),
+            next_stream_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn run_thread(mut receiver: Receiver<ParseMessage>, runtime: &Handle) {
+        // lets consumers `tokio::spawn` the follow-up work for the links they find
+        let _runtime_guard = runtime.enter();
+        let mut consumers: HashMap<u64, Box<dyn ChunkConsumer>> = HashMap::new();
+
+        while let Some(message) = receiver.blocking_recv() {
+            match message {
+                ParseMessage::Start(id, create_consumer) => {
+                    consumers.insert(id, create_consumer());
+                }
+                ParseMessage::Chunk(id, chunk) => {
+                    if let Some(consumer) = consumers.get_mut(&id) {
+                        consumer.push(&chunk);
+                    }
+                }
+                ParseMessage::Finish(id, finished) => {
+                    if let Some(consumer) = consumers.remove(&id) {
+                        consumer.finish();
+                    }
+                    let _ = finished.send(());
+                }
+            }
+        }
+    }
+
+    // Opens a stream to a new consumer, which `create_consumer` builds on its parser thread
+    pub(crate) async fn start(
+        &self,
+        create_consumer: impl FnOnce() -> Box<dyn ChunkConsumer> + Send + 'static,
+    ) -> Result<ParseStream> {
+        let id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let sender = self.senders[id as usize % self.senders.len()].clone();
+        let stream = ParseStream { id, sender };
+        stream
+            .send(ParseMessage::Start(id, Box::new(create_consumer)))
+            .await?;
+        Ok(stream)
+    }
+}
+
+// Feeds one document to its consumer. `finish` must be called once it's all been pushed,
+// or the consumer is never dropped.
+pub(crate) struct ParseStream {
+    id: u64,
+    sender: Sender<ParseMessage>,
+}
+
+impl ParseStream {
+    // Waits for room if the parser thread is behind
+    pub(crate) async fn push(&self, chunk: Bytes) -> Result<()> {
+        self.send(ParseMessage::Chunk(self.id, chunk)).await
+    }
+
+    // Waits until the consumer has finished with the document
+    pub(crate) async fn finish(self) -> Result<()> {
+        let (finished_sender, finished_receiver) = oneshot::channel();
+        self.send(ParseMessage::Finish(self.id, finished_sender))
+            .await?;
+        finished_receiver
+            .await
+            .map_err(|_| CrawlerError::ParsePoolClosed)?;
+        Ok(())
+    }
+
+    async fn send(&self, message: ParseMessage) -> Result<()> {
+        self.sender
+            .send(message)
+            .await
+            .map_err(|_| CrawlerError::ParsePoolClosed)?;
+        Ok(())
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Collects the chunks it's given, and sends them back along with its thread's name
+    struct Collector {
+        chunks: Vec<u8>,
+        reply: oneshot::Sender<(String, Vec<u8>)>,
+    }
+
+    impl ChunkConsumer for Collector {
+        fn push(&mut self, chunk: &[u8]) {
+            self.chunks.extend_from_slice(chunk);
+        }
+
+        fn finish(self: Box<Self>) {
+            let thread_name = thread::current().name().unwrap().to_string();
+            let Collector { chunks, reply } = *self;
+            // consumers can hand work back to the runtime
+            tokio::spawn(async move {
+                let _ = reply.send((thread_name, chunks));
+            });
+        }
+    }
+
+    async fn start_collector(
+        pool: &ParsePool,
+    ) -> (ParseStream, oneshot::Receiver<(String, Vec<u8>)>) {
+        let (reply, receiver) = oneshot::channel();
+        let stream = pool
+            .start(move || {
+                Box::new(Collector {
+                    chunks: Vec::new(),
+                    reply,
+                })
+            })
+            .await
+            .unwrap();
+        (stream, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_interleaved_streams() {
+        let pool = ParsePool::with_threads(2, 4);
+        let (first, first_reply) = start_collector(&pool).await;
+        let (second, second_reply) = start_collector(&pool).await;
+        let (third, third_reply) = start_collector(&pool).await;
+
+        for stream in [&first, &second, &third] {
+            stream.push(Bytes::from_static(b"ab")).await.unwrap();
+        }
+        first.push(Bytes::from_static(b"c")).await.unwrap();
+        third.push(Bytes::from_static(b"d")).await.unwrap();
+        for stream in [first, second, third] {
+            stream.finish().await.unwrap();
+        }
+
+        let (first_thread, first_chunks) = first_reply.await.unwrap();
+        let (second_thread, second_chunks) = second_reply.await.unwrap();
+        let (third_thread, third_chunks) = third_reply.await.unwrap();
+        assert_eq!(first_chunks, b"abc");
+        assert_eq!(second_chunks, b"ab");
+        assert_eq!(third_chunks, b"abd");
+        assert!(first_thread.starts_with("parser-"));
+        assert_ne!(first_thread, second_thread);
+        assert_eq!(first_thread, third_thread);
+    }
+
+    // Blocks its thread until told to carry on
+    struct Blocker(std::sync::mpsc::Receiver<()>);
+
+    impl ChunkConsumer for Blocker {
+        fn push(&mut self, _chunk: &[u8]) {
+            let _ = self.0.recv();
+        }
+
+        fn finish(self: Box<Self>) {}
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_waits() {
+        let pool = ParsePool::with_threads(1, 1);
+        let (release_sender, release_receiver) = std::sync::mpsc::channel();
+        let stream = pool
+            .start(move || Box::new(Blocker(release_receiver)))
+            .await
+            .unwrap();
+
+        // the thread blocks on the first chunk, the second fills the queue
+        stream.push(Bytes::from_static(b"1")).await.unwrap();
+        stream.push(Bytes::from_static(b"2")).await.unwrap();
+        let push = stream.push(Bytes::from_static(b"3"));
+        let timeout = std::time::Duration::from_millis(50);
+        assert!(tokio::time::timeout(timeout, push).await.is_err());
+
+        for _ in 0..3 {
+            release_sender.send(()).unwrap();
+        }
+        stream.push(Bytes::from_static(b"4")).await.unwrap();
+        release_sender.send(()).unwrap();
+        stream.finish().await.unwrap();
+    }
+}
@@ -1,144 +1,736 @@
-use std::str::Chars;
+use std::collections::{HashSet, VecDeque};
 
-pub(crate) fn find_anchors(html: &str, index: usize, max_index: usize) -> AnchorHrefIterator {
-    AnchorHrefIterator::new(html, index, max_index)
+use encoding_rs::{Decoder, Encoding, UTF_8};
+use html5ever::{
+    local_name,
+    tendril::StrTendril,
+    tokenizer::{
+        states::RawKind, BufferQueue, Tag, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer,
+        TokenizerOpts,
+    },
+    LocalName,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use shared::{LinkEdge, LinkKind};
+
+use crate::{
+    charset::{prescan, PRESCAN_LENGTH},
+    robots::{is_rel_nofollow, RobotsDirectives},
+};
+
+// Anchor text beyond this many bytes is dropped, so an unclosed `<a>` can't grow without bound
+const MAX_LINK_TEXT_LEN: usize = 1024;
+// The same goes for titles and headings, and for each JSON-LD block
+const MAX_METADATA_TEXT_LEN: usize = 1024;
+const MAX_JSON_LD_LEN: usize = 64 * 1024;
+// Open Graph tags and images without `alt` beyond this many are dropped
+const MAX_OPEN_GRAPH_TAGS: usize = 100;
+const MAX_IMAGES_WITHOUT_ALT: usize = 100;
+
+pub(crate) struct Link {
+    pub(crate) kind: LinkKind,
+    pub(crate) href: String,
+    pub(crate) text: Option<String>,
+    pub(crate) title: Option<String>,
+    pub(crate) rel: Option<String>,
+    pub(crate) hreflang: Option<String>,
+    pub(crate) target: Option<String>,
+    pub(crate) in_nav: bool,
+    pub(crate) line: u64,
+}
+
+impl Link {
+    pub(crate) fn is_nofollow(&self) -> bool {
+        self.rel.as_deref().is_some_and(is_rel_nofollow)
+    }
+
+    // The edge to the URL the link resolved to, whose fragment is kept on the edge
+    pub(crate) fn edge(&self, fragment: Option<String>) -> LinkEdge {
+        LinkEdge {
+            kind: self.kind,
+            text: self.text.clone(),
+            title: self.title.clone(),
+            rel: self.rel.clone(),
+            hreflang: self.hreflang.clone(),
+            target: self.target.clone(),
+            fragment,
+            in_nav: self.in_nav,
+            line: self.line,
+        }
+    }
+}
+
+// What a page says about itself, beyond its links
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PageMetadata {
+    pub(crate) title: Option<String>,
+    pub(crate) description: Option<String>,
+    // The href of `<link rel="canonical">`, as written in the document
+    pub(crate) canonical: Option<String>,
+    pub(crate) h1s: Vec<String>,
+    // The `lang` of the `<html>` element
+    pub(crate) lang: Option<String>,
+    // Words of the body's text, leaving out scripts and styles
+    pub(crate) word_count: u64,
+    // The `og:` properties of `<meta>` elements with their content, in document order
+    pub(crate) open_graph: Vec<(String, String)>,
+    // The contents of `<script type="application/ld+json">` elements, as written
+    pub(crate) json_ld: Vec<String>,
+    // The `src` of each `<img>` without an `alt`, as written. An empty `alt` marks an image
+    // as decorative, so those aren't included.
+    pub(crate) images_without_alt: Vec<String>,
 }
 
-pub(crate) struct AnchorHrefIterator<'a> {
-    html: Chars<'a>,
-    index: usize,
-    max_index: usize,
-    anchor_tag_counter: usize,
-    in_tag: bool,
-    in_anchor_tag_text: bool,
-    has_tag_name: bool,
-    tag_name: String,
-    current_attr: String,
-    current_value: String,
-    is_in_href: bool,
-    is_in_value: bool,
-    quote_char: Option<char>,
-    pending_href: Option<String>,
+// Everything parsing a whole page found besides its links
+#[derive(Default)]
+pub(crate) struct PageAnalysis {
+    pub(crate) metadata: PageMetadata,
+    // A hash of the document's text with its whitespace collapsed, leaving out scripts, styles
+    // and the markup itself, so only changes a reader would see count
+    pub(crate) content_hash: String,
+    // The `id`s of the document's elements, and the `name`s of its anchors, which links can
+    // point at with a `#fragment`
+    pub(crate) anchors: HashSet<String>,
 }
 
-impl<'a> AnchorHrefIterator<'a> {
-    fn new(html: &'a str, index: usize, max_index: usize) -> Self {
+// Finds links in a document that's pushed in as it downloads, one chunk of bytes at a time.
+// Chunks may end anywhere, even in the middle of a tag or of a multi-byte character.
+pub(crate) struct LinkParser {
+    // Set once the document's encoding is known
+    decoder: Option<Decoder>,
+    // The start of the document, held back until then
+    prescan_buffer: Vec<u8>,
+    tokenizer: Tokenizer<LinkSink>,
+    input: BufferQueue,
+    finished: bool,
+}
+
+impl LinkParser {
+    // The encoding is sniffed the way browsers do: a byte order mark wins, then the encoding
+    // from the `Content-Type` header, then a `<meta>` declaration at the start of the document,
+    // falling back to UTF-8
+    pub(crate) fn new(link_kinds: &[LinkKind], header_encoding: Option<&'static Encoding>) -> Self {
         Self {
-            html: html.chars(),
-            index,
-            max_index,
-            anchor_tag_counter: 0,
-            in_tag: false,
-            in_anchor_tag_text: false,
-            has_tag_name: false,
-            tag_name: String::new(),
-            current_attr: String::new(),
-            current_value: String::new(),
-            is_in_href: false,
-            is_in_value: false,
-            quote_char: None,
-            pending_href: None,
+            // the decoder replaces its encoding with the byte order mark's, if there is one
+            decoder: header_encoding.map(Encoding::new_decoder),
+            prescan_buffer: Vec::new(),
+            tokenizer: Tokenizer::new(LinkSink::new(link_kinds), TokenizerOpts::default()),
+            input: BufferQueue::default(),
+            finished: false,
+        }
+    }
+
+    pub(crate) fn push(&mut self, chunk: &[u8]) {
+        if self.decoder.is_some() {
+            self.decode(chunk, false);
+            return;
+        }
+        self.prescan_buffer.extend_from_slice(chunk);
+        if self.prescan_buffer.len() >= PRESCAN_LENGTH {
+            self.decode_prescan_buffer(false);
         }
     }
+
+    // Called once the whole document has been pushed
+    pub(crate) fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        if self.decoder.is_none() {
+            self.decode_prescan_buffer(true);
+        } else {
+            self.decode(&[], true);
+        }
+        self.tokenizer.end();
+        // an element left open at the end of the document ends with it
+        self.tokenizer.sink.end_capture();
+        self.finished = true;
+    }
+
+    // The encoding the document is decoded with, once it's known
+    pub(crate) fn encoding(&self) -> &'static Encoding {
+        self.decoder.as_ref().map_or(UTF_8, Decoder::encoding)
+    }
+
+    fn decode_prescan_buffer(&mut self, last: bool) {
+        let encoding = prescan(&self.prescan_buffer).unwrap_or(UTF_8);
+        self.decoder = Some(encoding.new_decoder());
+        let buffer = std::mem::take(&mut self.prescan_buffer);
+        self.decode(&buffer, last);
+    }
+
+    fn decode(&mut self, chunk: &[u8], last: bool) {
+        let Some(decoder) = self.decoder.as_mut() else {
+            return;
+        };
+        // the decoder keeps a character split across chunks until the rest of it arrives
+        let max_length = decoder.max_utf8_buffer_length(chunk.len()).unwrap_or(0);
+        let mut text = String::with_capacity(max_length);
+        let _ = decoder.decode_to_string(chunk, &mut text, last);
+        if !text.is_empty() {
+            self.input.push_back(StrTendril::from(text));
+            let _ = self.tokenizer.feed(&mut self.input);
+        }
+    }
+
+    // The next link found so far, if it's ready to be yielded
+    pub(crate) fn next_link(&mut self) -> Option<Link> {
+        self.tokenizer.sink.pop_link(self.finished)
+    }
+
+    // The href of the document's `<base>` element, which relative links resolve against.
+    // Links from the `<head>` aren't yielded until it's done, so they see the base too.
+    pub(crate) fn base_href(&self) -> Option<&str> {
+        self.tokenizer.sink.base_href.as_deref()
+    }
+
+    // The directives of the document's `<meta name="robots">` elements, which are also known
+    // by the time links are yielded
+    pub(crate) fn robots(&self) -> RobotsDirectives {
+        self.tokenizer.sink.robots
+    }
+
+    // What the document says about itself, complete once it's finished
+    pub(crate) fn take_analysis(&mut self) -> PageAnalysis {
+        let sink = &mut self.tokenizer.sink;
+        PageAnalysis {
+            metadata: std::mem::take(&mut sink.metadata),
+            content_hash: format!("{:x}", sink.content_hasher.clone().finalize()),
+            anchors: std::mem::take(&mut sink.anchors),
+        }
+    }
+}
+
+// An element whose text is being read into the page's metadata
+#[derive(Clone, Copy, PartialEq)]
+enum TextCapture {
+    Title,
+    H1,
+    JsonLd,
+}
+
+// Receives tokens from the HTML5 tokenizer and collects the links of the requested kinds
+struct LinkSink {
+    link_kinds: HashSet<LinkKind>,
+    links: VecDeque<Link>,
+    in_head: bool,
+    in_raw_text: bool,
+    base_href: Option<String>,
+    robots: RobotsDirectives,
+    // The position in `links` of the anchor whose text is still being read
+    open_anchor: Option<usize>,
+    line: u64,
+    content_hasher: Sha256,
+    // Whether the text being read is part of the content, rather than a script or style
+    in_content_text: bool,
+    // Whitespace, or a tag, since the last text that was hashed
+    pending_space: bool,
+    hashed_any: bool,
+    anchors: HashSet<String>,
+    metadata: PageMetadata,
+    // How many `<nav>` elements are open
+    nav_depth: usize,
+    // How many `<svg>` and `<math>` elements are open, and whether one's `<title>` is
+    foreign_depth: usize,
+    in_foreign_title: bool,
+    capture: Option<TextCapture>,
+    captured_text: String,
 }
 
-impl<'a> Iterator for AnchorHrefIterator<'a> {
-    type Item = String;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        while let Some(c) = self.html.next() {
-            match c {
-                '<' => {
-                    self.in_tag = true;
-                    self.tag_name.clear();
-                    self.has_tag_name = false;
-                    self.current_attr.clear();
-                    self.current_value.clear();
-                    self.is_in_href = false;
-                    self.is_in_value = false;
-                    self.quote_char = None;
+impl LinkSink {
+    fn new(link_kinds: &[LinkKind]) -> Self {
+        Self {
+            link_kinds: link_kinds.iter().copied().collect(),
+            links: VecDeque::new(),
+            in_head: true,
+            in_raw_text: false,
+            base_href: None,
+            robots: RobotsDirectives::default(),
+            open_anchor: None,
+            line: 1,
+            content_hasher: Sha256::new(),
+            in_content_text: true,
+            pending_space: false,
+            hashed_any: false,
+            anchors: HashSet::new(),
+            metadata: PageMetadata::default(),
+            nav_depth: 0,
+            foreign_depth: 0,
+            in_foreign_title: false,
+            capture: None,
+            captured_text: String::new(),
+        }
+    }
+
+    // Holds links back while a `<base>` element could still turn up, and from the anchor
+    // whose text is still being read onwards, so links are yielded in document order
+    fn pop_link(&mut self, finished: bool) -> Option<Link> {
+        if finished {
+            self.close_anchor();
+        } else if self.in_head || self.open_anchor == Some(0) {
+            return None;
+        }
+        let link = self.links.pop_front()?;
+        if let Some(index) = self.open_anchor.as_mut() {
+            *index -= 1;
+        }
+        Some(link)
+    }
+
+    fn handle_tag(&mut self, tag: Tag) -> TokenSinkResult<()> {
+        // tags separate words the way whitespace does
+        self.pending_space = true;
+        if tag.kind != TagKind::StartTag {
+            self.in_raw_text = false;
+            self.in_content_text = true;
+            match tag.name {
+                local_name!("head") => self.in_head = false,
+                local_name!("a") => self.close_anchor(),
+                local_name!("title") => {
+                    self.in_foreign_title = false;
+                    self.end_capture_of(TextCapture::Title);
                 }
-                '>' => {
-                    if !self.in_tag {
-                        continue;
-                    }
-                    self.in_tag = false;
-                    // Handle opening and closing tags
-                    if self.tag_name == "a" {
-                        // Opening tag
-                        self.in_anchor_tag_text = true;
-                    } else if self.tag_name.starts_with('/') {
-                        // Closing tag
-                        let closing_tag_name = self.tag_name.trim_start_matches('/');
-                        if closing_tag_name == "a" && self.in_anchor_tag_text {
-                            self.in_anchor_tag_text = false;
-                            // Increment the anchor tag counter when we close an <a> tag
-                            if let Some(href) = self.pending_href.take() {
-                                if (self.anchor_tag_counter % self.max_index) == self.index {
-                                    self.anchor_tag_counter += 1;
-                                    return Some(href);
-                                }
-                                self.anchor_tag_counter += 1;
-                            }
-                        }
-                    }
+                local_name!("h1") => self.end_capture_of(TextCapture::H1),
+                local_name!("script") => self.end_capture_of(TextCapture::JsonLd),
+                local_name!("nav") => self.nav_depth = self.nav_depth.saturating_sub(1),
+                local_name!("svg") | local_name!("math") => {
+                    self.foreign_depth = self.foreign_depth.saturating_sub(1)
                 }
-                c => {
-                    if !self.in_tag {
-                        continue;
-                    }
-                    if !self.has_tag_name {
-                        if c.is_whitespace() {
-                            if self.tag_name.len() > 0 {
-                                self.has_tag_name = true;
-                            }
-                        } else {
-                            self.tag_name.push(c);
-                        }
-                    } else if self.tag_name != "a" {
-                        // skip the remainder of this tag
-                    } else if !self.is_in_value {
-                        if c.is_whitespace() {
-                            // skip whitespace
-                        } else if c == '=' {
-                            self.is_in_value = true;
-                            self.current_value.clear();
-                            self.quote_char = None;
-
-                            // Check if the current attribute is "href"
-                            if self.current_attr == "href" {
-                                self.is_in_href = true;
-                            }
-                        } else if c != '/' {
-                            self.current_attr.push(c);
-                        }
-                    } else if self.is_in_value {
-                        if self.quote_char.is_none() {
-                            if c == '"' || c == '\'' {
-                                self.quote_char = Some(c);
-                            }
-                        } else if Some(c) == self.quote_char {
-                            // End of attribute value
-
-                            if self.is_in_href {
-                                // Store the href to yield after closing tag
-                                self.pending_href = Some(self.current_value.clone());
-                            }
-
-                            self.is_in_value = false;
-                            self.is_in_href = false;
-                            self.current_attr.clear();
-                            self.current_value.clear();
-                            self.quote_char = None;
-                        } else {
-                            // Inside quoted value
-                            self.current_value.push(c);
-                        }
+                _ => {}
+            }
+            return TokenSinkResult::Continue;
+        }
+        if !is_head_element(&tag.name) {
+            self.in_head = false;
+        }
+        if let Some(id) = get_attr(&tag, local_name!("id")) {
+            self.anchors.insert(id.to_string());
+        }
+
+        // The start tag's attributes are complete, so links are queued right away, whether or
+        // not the element is ever closed. Only an anchor's text has to wait for its end.
+        match tag.name {
+            local_name!("a") => {
+                // anchors can't nest, so a new one ends the text of the last
+                self.close_anchor();
+                if let Some(name) = get_attr(&tag, local_name!("name")) {
+                    self.anchors.insert(name.to_string());
+                }
+                self.push_attr(&tag, LinkKind::AnchorHref, local_name!("href"));
+            }
+            local_name!("area") => self.push_attr(&tag, LinkKind::AreaHref, local_name!("href")),
+            local_name!("link") => {
+                self.read_canonical(&tag);
+                self.push_attr(&tag, LinkKind::LinkHref, local_name!("href"))
+            }
+            local_name!("iframe") => self.push_attr(&tag, LinkKind::IframeSrc, local_name!("src")),
+            local_name!("frame") => self.push_attr(&tag, LinkKind::FrameSrc, local_name!("src")),
+            local_name!("img") => {
+                if let Some(alt) = get_attr(&tag, local_name!("alt")) {
+                    // spaced out, so it doesn't run into the text around the image
+                    self.push_anchor_text(&format!(" {} ", alt));
+                } else if self.metadata.images_without_alt.len() < MAX_IMAGES_WITHOUT_ALT {
+                    let src = get_attr(&tag, local_name!("src")).unwrap_or_default();
+                    self.metadata.images_without_alt.push(src.to_string());
+                }
+                self.push_attr(&tag, LinkKind::ImgSrc, local_name!("src"));
+                self.push_srcset(&tag, LinkKind::ImgSrcset);
+            }
+            local_name!("script") => {
+                let is_json_ld = get_attr(&tag, local_name!("type"))
+                    .is_some_and(|value| value.trim().eq_ignore_ascii_case("application/ld+json"));
+                if is_json_ld {
+                    self.start_capture(TextCapture::JsonLd);
+                }
+                self.push_attr(&tag, LinkKind::ScriptSrc, local_name!("src"))
+            }
+            local_name!("source") => {
+                self.push_attr(&tag, LinkKind::SourceSrc, local_name!("src"));
+                self.push_srcset(&tag, LinkKind::SourceSrcset);
+            }
+            local_name!("form") => {
+                self.push_attr(&tag, LinkKind::FormAction, local_name!("action"))
+            }
+            local_name!("meta") => {
+                self.push_meta_refresh(&tag);
+                self.read_meta_robots(&tag);
+                self.read_meta_metadata(&tag);
+            }
+            local_name!("html") => {
+                if self.metadata.lang.is_none() {
+                    self.metadata.lang = get_attr(&tag, local_name!("lang")).map(str::to_string);
+                }
+            }
+            local_name!("title") => {
+                // an SVG's `<title>` names the image, not the page
+                if self.foreign_depth > 0 {
+                    self.in_foreign_title = true;
+                } else if self.metadata.title.is_none() {
+                    self.start_capture(TextCapture::Title);
+                }
+            }
+            local_name!("svg") | local_name!("math") if !tag.self_closing => {
+                self.foreign_depth += 1
+            }
+            local_name!("h1") => self.start_capture(TextCapture::H1),
+            local_name!("nav") => self.nav_depth += 1,
+            local_name!("base") => {
+                // only the first base element with an href counts
+                if self.base_href.is_none() {
+                    self.base_href = get_attr(&tag, local_name!("href")).map(str::to_string);
+                }
+            }
+            local_name!("object") => {
+                self.push_attr(&tag, LinkKind::ObjectData, local_name!("data"))
+            }
+            _ => {}
+        }
+
+        let text_state = text_state_after(&tag.name);
+        self.in_raw_text = text_state != TokenSinkResult::Continue;
+        self.in_content_text = !matches!(
+            text_state,
+            TokenSinkResult::RawData(RawKind::ScriptData | RawKind::Rawtext)
+        );
+        text_state
+    }
+
+    // Hashes the text a word at a time, with a single space between words however they were
+    // separated, so the same text hashes the same whichever chunks it arrived in. The words of
+    // the body are counted along the way.
+    fn hash_content_text(&mut self, text: &str) {
+        if !self.in_content_text {
+            return;
+        }
+        for (index, word) in text.split(char::is_whitespace).enumerate() {
+            if index > 0 {
+                self.pending_space = true;
+            }
+            if word.is_empty() {
+                continue;
+            }
+            // the rest of a word that was split across chunks isn't a word of its own
+            let starts_word = self.pending_space || !self.hashed_any;
+            if starts_word && !self.in_head {
+                self.metadata.word_count += 1;
+            }
+            if self.pending_space {
+                self.content_hasher.update(b" ");
+                self.pending_space = false;
+            }
+            self.content_hasher.update(word.as_bytes());
+            self.hashed_any = true;
+        }
+    }
+
+    // Only one element's text is read at a time, so e.g. a title inside an `<h1>` is left
+    // out of it
+    fn start_capture(&mut self, capture: TextCapture) {
+        if self.capture.is_none() {
+            self.capture = Some(capture);
+            self.captured_text.clear();
+        }
+    }
+
+    fn push_captured_text(&mut self, text: &str) {
+        let max_length = match self.capture {
+            None => return,
+            // text in a script or an SVG's title inside the heading isn't part of it
+            Some(TextCapture::H1) if !self.in_content_text || self.in_foreign_title => return,
+            Some(TextCapture::Title | TextCapture::H1) => MAX_METADATA_TEXT_LEN,
+            Some(TextCapture::JsonLd) => MAX_JSON_LD_LEN,
+        };
+        if self.captured_text.len() >= max_length {
+            return;
+        }
+        let mut end = text.len().min(max_length - self.captured_text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.captured_text.push_str(&text[..end]);
+    }
+
+    // Ends the capture only if it's the one the end tag closes, so e.g. a `</script>` inside
+    // a heading doesn't end the heading
+    fn end_capture_of(&mut self, capture: TextCapture) {
+        if self.capture == Some(capture) {
+            self.end_capture();
+        }
+    }
+
+    fn end_capture(&mut self) {
+        let Some(capture) = self.capture.take() else {
+            return;
+        };
+        let text = std::mem::take(&mut self.captured_text);
+        match capture {
+            TextCapture::Title => self.metadata.title = Some(collapse_whitespace(&text)),
+            TextCapture::H1 => self.metadata.h1s.push(collapse_whitespace(&text)),
+            TextCapture::JsonLd => self.metadata.json_ld.push(text.trim().to_string()),
+        }
+    }
+
+    fn push(&mut self, tag: &Tag, kind: LinkKind, href: &str) {
+        if href.trim().is_empty() {
+            return;
+        }
+        let text = match kind {
+            LinkKind::AnchorHref => Some(String::new()),
+            LinkKind::AreaHref => Some(get_attr(tag, local_name!("alt")).unwrap_or("").to_string()),
+            _ => None,
+        };
+        if kind == LinkKind::AnchorHref {
+            self.open_anchor = Some(self.links.len());
+        }
+        let attr = |name| get_attr(tag, name).map(str::to_string);
+        self.links.push_back(Link {
+            kind,
+            href: href.to_string(),
+            text: text.map(|text| collapse_whitespace(&text)),
+            title: attr(local_name!("title")),
+            rel: attr(local_name!("rel")),
+            hreflang: attr(local_name!("hreflang")),
+            target: attr(local_name!("target")),
+            in_nav: self.nav_depth > 0,
+            line: self.line,
+        });
+    }
+
+    fn push_anchor_text(&mut self, text: &str) {
+        let Some(index) = self.open_anchor else {
+            return;
+        };
+        let Some(anchor_text) = self.links[index].text.as_mut() else {
+            return;
+        };
+        if anchor_text.len() >= MAX_LINK_TEXT_LEN {
+            return;
+        }
+        let mut end = text.len().min(MAX_LINK_TEXT_LEN - anchor_text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        anchor_text.push_str(&text[..end]);
+    }
+
+    fn close_anchor(&mut self) {
+        if let Some(index) = self.open_anchor.take() {
+            let link = &mut self.links[index];
+            link.text = link.text.as_deref().map(collapse_whitespace);
+        }
+    }
+
+    fn push_attr(&mut self, tag: &Tag, kind: LinkKind, attr_name: LocalName) {
+        if !self.link_kinds.contains(&kind) {
+            return;
+        }
+        if let Some(value) = get_attr(tag, attr_name) {
+            self.push(tag, kind, value);
+        }
+    }
+
+    fn push_srcset(&mut self, tag: &Tag, kind: LinkKind) {
+        if !self.link_kinds.contains(&kind) {
+            return;
+        }
+        if let Some(srcset) = get_attr(tag, local_name!("srcset")) {
+            for href in parse_srcset(srcset) {
+                self.push(tag, kind, href);
+            }
+        }
+    }
+
+    fn push_meta_refresh(&mut self, tag: &Tag) {
+        if !self.link_kinds.contains(&LinkKind::MetaRefresh) {
+            return;
+        }
+        let is_refresh = get_attr(tag, local_name!("http-equiv"))
+            .is_some_and(|value| value.trim().eq_ignore_ascii_case("refresh"));
+        if !is_refresh {
+            return;
+        }
+        if let Some(href) = get_attr(tag, local_name!("content")).and_then(parse_meta_refresh) {
+            self.push(tag, LinkKind::MetaRefresh, href);
+        }
+    }
+
+    // `<meta name="description">` and the Open Graph `<meta property="og:...">` tags
+    fn read_meta_metadata(&mut self, tag: &Tag) {
+        let Some(content) = get_attr(tag, local_name!("content")) else {
+            return;
+        };
+        let is_description = get_attr(tag, local_name!("name"))
+            .is_some_and(|name| name.trim().eq_ignore_ascii_case("description"));
+        if is_description && self.metadata.description.is_none() {
+            self.metadata.description = Some(collapse_whitespace(content));
+        }
+
+        let property = get_attr(tag, local_name!("property")).map(str::to_ascii_lowercase);
+        if let Some(property) = property.filter(|property| property.starts_with("og:")) {
+            if self.metadata.open_graph.len() < MAX_OPEN_GRAPH_TAGS {
+                let content = collapse_whitespace(content);
+                self.metadata.open_graph.push((property, content));
+            }
+        }
+    }
+
+    fn read_canonical(&mut self, tag: &Tag) {
+        let is_canonical = get_attr(tag, local_name!("rel")).is_some_and(|rel| {
+            rel.split_ascii_whitespace()
+                .any(|value| value.eq_ignore_ascii_case("canonical"))
+        });
+        if is_canonical && self.metadata.canonical.is_none() {
+            self.metadata.canonical = get_attr(tag, local_name!("href")).map(str::to_string);
+        }
+    }
+
+    fn read_meta_robots(&mut self, tag: &Tag) {
+        let is_robots = get_attr(tag, local_name!("name"))
+            .is_some_and(|name| name.trim().eq_ignore_ascii_case("robots"));
+        if !is_robots {
+            return;
+        }
+        if let Some(content) = get_attr(tag, local_name!("content")) {
+            self.robots.merge(RobotsDirectives::parse(content));
+        }
+    }
+}
+
+impl TokenSink for LinkSink {
+    type Handle = ();
+
+    fn process_token(&mut self, token: Token, line_number: u64) -> TokenSinkResult<()> {
+        self.line = line_number;
+        match token {
+            Token::TagToken(tag) => self.handle_tag(tag),
+            Token::CharacterTokens(text) => {
+                if !self.in_raw_text {
+                    // text outside of raw text elements means the body has started
+                    if !text.trim().is_empty() {
+                        self.in_head = false;
                     }
+                    self.push_anchor_text(&text);
                 }
+                self.hash_content_text(&text);
+                self.push_captured_text(&text);
+                TokenSinkResult::Continue
             }
+            _ => TokenSinkResult::Continue,
+        }
+    }
+}
+
+fn is_head_element(tag_name: &LocalName) -> bool {
+    matches!(
+        *tag_name,
+        local_name!("html")
+            | local_name!("head")
+            | local_name!("base")
+            | local_name!("link")
+            | local_name!("meta")
+            | local_name!("script")
+            | local_name!("style")
+            | local_name!("title")
+            | local_name!("noscript")
+            | local_name!("template")
+    )
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn get_attr(tag: &Tag, name: LocalName) -> Option<&str> {
+    tag.attrs
+        .iter()
+        .find(|attr| attr.name.local == name)
+        .map(|attr| &*attr.value)
+}
+
+// Without a tree builder, the tokenizer has to be told which elements contain raw text rather
+// than markup, so e.g. an `<a>` inside a `<script>` isn't mistaken for a link
+fn text_state_after(tag_name: &LocalName) -> TokenSinkResult<()> {
+    match *tag_name {
+        local_name!("script") => TokenSinkResult::RawData(RawKind::ScriptData),
+        local_name!("style")
+        | local_name!("xmp")
+        | local_name!("iframe")
+        | local_name!("noembed")
+        | local_name!("noframes") => TokenSinkResult::RawData(RawKind::Rawtext),
+        local_name!("title") | local_name!("textarea") => TokenSinkResult::RawData(RawKind::Rcdata),
+        local_name!("plaintext") => TokenSinkResult::Plaintext,
+        _ => TokenSinkResult::Continue,
+    }
+}
+
+// The URLs of a `srcset` attribute, e.g. `small.png 1x, large.png 2x`
+fn parse_srcset(srcset: &str) -> Vec<&str> {
+    let mut urls = Vec::new();
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == ',');
+        if rest.is_empty() {
+            return urls;
+        }
+
+        let url_end = rest
+            .find(|c: char| c.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        let url = &rest[..url_end];
+        rest = &rest[url_end..];
+
+        if let Some(url) = url.strip_suffix(',') {
+            // a candidate without descriptors
+            urls.push(url.trim_end_matches(','));
+            continue;
+        }
+        urls.push(url);
+
+        // skip the descriptors, which may contain commas inside parentheses
+        let mut depth = 0;
+        let descriptors_end = rest
+            .find(|c: char| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    ',' if depth <= 0 => return true,
+                    _ => {}
+                }
+                false
+            })
+            .unwrap_or(rest.len());
+        rest = &rest[descriptors_end..];
+    }
+}
+
+// The URL of a `<meta http-equiv="refresh">` content value, e.g. `5; url='/next'`
+fn parse_meta_refresh(content: &str) -> Option<&str> {
+    let rest = content.trim_start_matches(|c: char| c.is_ascii_whitespace());
+    let rest = rest.trim_start_matches(|c: char| c.is_ascii_digit() || c == '.');
+    let rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace());
+    let rest = rest.strip_prefix([';', ','])?;
+    let mut rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace());
+
+    if rest.len() >= 3 && rest[..3].eq_ignore_ascii_case("url") {
+        let after_url = rest[3..].trim_start_matches(|c: char| c.is_ascii_whitespace());
+        if let Some(after_equals) = after_url.strip_prefix('=') {
+            rest = after_equals.trim_start_matches(|c: char| c.is_ascii_whitespace());
+        }
+    }
+
+    let url = match rest.chars().next() {
+        Some(quote @ ('"' | '\'')) => {
+            let rest = &rest[1..];
+            &rest[..rest.find(quote).unwrap_or(rest.len())]
         }
+        _ => rest.trim_end(),
+    };
+    if url.is_empty() {
         None
+    } else {
+        Some(url)
     }
 }
 
@@ -148,23 +740,77 @@ impl<'a> Iterator for AnchorHrefIterator<'a> {
 mod tests {
     use super::*;
 
+    use encoding_rs::{SHIFT_JIS, WINDOWS_1252};
+
+    // Documents are pushed in slices of this many bytes, the way they arrive from the network
+    const CHUNK_SIZE: usize = 16 * 1024;
+
+    // Pushes the document a chunk at a time, yielding links as soon as they're ready
+    struct Links<'a> {
+        parser: LinkParser,
+        html: &'a [u8],
+        chunk_size: usize,
+    }
+
+    impl Links<'_> {
+        fn base_href(&self) -> Option<&str> {
+            self.parser.base_href()
+        }
+
+        fn robots(&self) -> RobotsDirectives {
+            self.parser.robots()
+        }
+    }
+
+    impl Iterator for Links<'_> {
+        type Item = Link;
+
+        fn next(&mut self) -> Option<Link> {
+            loop {
+                if let Some(link) = self.parser.next_link() {
+                    return Some(link);
+                }
+                if self.html.is_empty() {
+                    if self.parser.finished {
+                        return None;
+                    }
+                    self.parser.finish();
+                } else {
+                    let (chunk, rest) = self.html.split_at(self.chunk_size.min(self.html.len()));
+                    self.html = rest;
+                    self.parser.push(chunk);
+                }
+            }
+        }
+    }
+
+    fn find_links<'a>(html: &'a str, link_kinds: &[LinkKind]) -> Links<'a> {
+        Links {
+            parser: LinkParser::new(link_kinds, Some(UTF_8)),
+            html: html.as_bytes(),
+            chunk_size: CHUNK_SIZE,
+        }
+    }
+
+    fn find_anchor_hrefs(html: &str) -> Vec<String> {
+        find_links(html, &[LinkKind::AnchorHref])
+            .map(|link| link.href)
+            .collect()
+    }
+
     #[test]
     fn test_normal_case() {
         let html = r#"
             <a href="e">E</a>
         "#;
-        let index = 0;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
         assert_eq!(hrefs, vec!["e",]);
     }
 
     #[test]
     fn test_empty_html() {
         let html = "";
-        let index = 0;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
         assert!(hrefs.is_empty());
     }
 
@@ -174,9 +820,7 @@ mod tests {
             <div>No anchor tags here</div>
             <p>Just some text</p>
         "#;
-        let index = 0;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
         assert!(hrefs.is_empty());
     }
 
@@ -185,38 +829,43 @@ mod tests {
         let html = r#"
             <a href="https://example.com">Example
         "#;
-        let index = 0;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
-        // The parser should not yield the href since there's no closing </a> tag
-        assert!(hrefs.is_empty());
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
+        // The href is yielded from the start tag, even without a closing </a> tag
+        assert_eq!(hrefs, vec!["https://example.com"]);
     }
 
     #[test]
-    fn test_different_index_and_max_index() {
+    fn test_unclosed_anchors() {
         let html = r#"
-            <a href="https://example1.com">Example 1</a>
-            <a href="https://example2.com">Example 2</a>
-            <a href="https://example3.com">Example 3</a>
-            <a href="https://example4.com">Example 4</a>
+            <ul>
+                <li><a href="/one">One
+                <li><a href="/two">Two
+                <li><a href="/three">Three
+            </ul>
         "#;
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
+        assert_eq!(hrefs, vec!["/one", "/two", "/three"]);
+    }
 
-        // Worker 0
-        let index = 0;
-        let max_index = 2;
-        let hrefs_worker_0: Vec<String> = find_anchors(html, index, max_index).collect();
-        assert_eq!(
-            hrefs_worker_0,
-            vec!["https://example1.com", "https://example3.com"]
-        );
+    #[test]
+    fn test_nested_and_overlapping_anchors() {
+        let html = r#"
+            <a href="/outer">Outer <a href="/inner">Inner</a> text</a>
+            <a href="/first"><b>First <a href="/second">Second</b></a>
+        "#;
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
+        assert_eq!(hrefs, vec!["/outer", "/inner", "/first", "/second"]);
+    }
 
-        // Worker 1
-        let index = 1;
-        let hrefs_worker_1: Vec<String> = find_anchors(html, index, max_index).collect();
-        assert_eq!(
-            hrefs_worker_1,
-            vec!["https://example2.com", "https://example4.com"]
-        );
+    #[test]
+    fn test_self_closed_anchors() {
+        let html = r#"
+            <a href="/self-closed"/>
+            <a href="/also-self-closed" />Text
+            <a href="/normal">Normal</a>
+        "#;
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
+        assert_eq!(hrefs, vec!["/self-closed", "/also-self-closed", "/normal"]);
     }
 
     #[test]
@@ -225,9 +874,7 @@ mod tests {
             <a id="link1" href="https://example.com">Example</a>
             <a href="https://example.org" class="external">Example Org</a>
         "#;
-        let index = 0;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
         assert_eq!(hrefs, vec!["https://example.com", "https://example.org"]);
     }
 
@@ -238,9 +885,7 @@ mod tests {
             <a href="https://example.com">Valid Link</a>
             <a>No href again</a>
         "#;
-        let index = 0;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
         assert_eq!(hrefs, vec!["https://example.com"]);
     }
 
@@ -253,9 +898,7 @@ mod tests {
                 </a>
             </div>
         "#;
-        let index = 0;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
         assert_eq!(hrefs, vec!["https://example.com"]);
     }
 
@@ -265,9 +908,7 @@ mod tests {
             <a href='https://example.com'>Example</a>
             <a href='https://example.org'>Example Org</a>
         "#;
-        let index = 0;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
         assert_eq!(hrefs, vec!["https://example.com", "https://example.org"]);
     }
 
@@ -280,9 +921,7 @@ mod tests {
                 i, i
             ));
         }
-        let index = 0;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(&html, index, max_index).collect();
+        let hrefs: Vec<String> = find_anchor_hrefs(&html);
         assert_eq!(hrefs.len(), 1000);
         assert_eq!(hrefs[0], "https://example1.com");
         assert_eq!(hrefs[999], "https://example1000.com");
@@ -294,9 +933,7 @@ mod tests {
             <a href="https://пример.рф">Unicode Domain</a>
             <a href="https://example.com/路径">Unicode Path</a>
         "#;
-        let index = 0;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
         assert_eq!(hrefs, vec!["https://пример.рф", "https://example.com/路径"]);
     }
 
@@ -306,9 +943,7 @@ mod tests {
             <a href="https://example.com?param=1&other=2">Example</a>
             <a href="https://example.org/#fragment">Example Org</a>
         "#;
-        let index = 0;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
         assert_eq!(
             hrefs,
             vec![
@@ -324,48 +959,574 @@ mod tests {
             <a class="link" data-id="123" href="https://example.com">Example</a>
             <a id="link2" href="https://example.org" title="Example Org">Example Org</a>
         "#;
-        let index = 0;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
         assert_eq!(hrefs, vec!["https://example.com", "https://example.org"]);
     }
 
     #[test]
-    fn test_max_index_greater_than_number_of_anchors() {
+    fn test_uppercase_tags_and_attributes() {
         let html = r#"
-            <a href="https://example1.com">Example 1</a>
-            <a href="https://example2.com">Example 2</a>
+            <A HREF="https://example.com">Example</A>
+            <a Href="https://example.org">Example Org</a>
         "#;
-        let index = 0;
-        let max_index = 5; // Greater than the number of anchors
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
-        // Only process anchor tags where (anchor_tag_counter % 5) == 0
-        assert_eq!(hrefs, vec!["https://example1.com"]);
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
+        assert_eq!(hrefs, vec!["https://example.com", "https://example.org"]);
     }
 
     #[test]
-    fn test_zero_max_index() {
+    fn test_unquoted_attribute_values() {
         let html = r#"
+            <a href=https://example.com/page class=link>Example</a>
+            <a class=link href=/relative/path>Relative</a>
+        "#;
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
+        assert_eq!(hrefs, vec!["https://example.com/page", "/relative/path"]);
+    }
+
+    #[test]
+    fn test_comments_are_ignored() {
+        let html = r#"
+            <!-- <a href="https://commented.com">Commented</a> -->
             <a href="https://example.com">Example</a>
+            <!--> <a href="https://after-abrupt-comment.com">Abrupt</a>
         "#;
-        let index = 0;
-        let max_index = 0;
-        // Should handle division by zero or invalid max_index gracefully
-        let result = std::panic::catch_unwind(|| {
-            let _hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
-        });
-        assert!(result.is_err());
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
+        assert_eq!(
+            hrefs,
+            vec!["https://example.com", "https://after-abrupt-comment.com"]
+        );
     }
 
     #[test]
-    fn test_invalid_index() {
+    fn test_script_and_style_contents_are_ignored() {
         let html = r#"
+            <script>document.write('<a href="https://script.com">Script</a>');</script>
+            <style>/* <a href="https://style.com">Style</a> */</style>
+            <title><a href="https://title.com">Title</a></title>
             <a href="https://example.com">Example</a>
         "#;
-        // Since usize cannot be negative, we'll test with an invalid index
-        let index = usize::MAX;
-        let max_index = 1;
-        let hrefs: Vec<String> = find_anchors(html, index, max_index).collect();
-        assert!(hrefs.is_empty());
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
+        assert_eq!(hrefs, vec!["https://example.com"]);
+    }
+
+    #[test]
+    fn test_entities_are_decoded() {
+        let html = r#"
+            <a href="https://example.com/?a=1&amp;b=2">Example</a>
+            <a href="/caf&eacute;?q=&#x31;">Cafe</a>
+        "#;
+        let hrefs: Vec<String> = find_anchor_hrefs(html);
+        assert_eq!(hrefs, vec!["https://example.com/?a=1&b=2", "/café?q=1"]);
+    }
+
+    #[test]
+    fn test_tags_split_across_chunks() {
+        let mut html = " ".repeat(CHUNK_SIZE - 10);
+        html.push_str(r#"<a href="https://example.com/split">Split</a>"#);
+        html.push_str(&"é".repeat(CHUNK_SIZE));
+        html.push_str(r#"<a href="https://example.com/after">After</a>"#);
+        let hrefs: Vec<String> = find_anchor_hrefs(&html);
+        assert_eq!(
+            hrefs,
+            vec!["https://example.com/split", "https://example.com/after"]
+        );
+    }
+
+    fn analyze(html: &str, chunk_size: usize) -> PageAnalysis {
+        let mut links = find_links(html, &[]);
+        links.chunk_size = chunk_size;
+        links.by_ref().for_each(drop);
+        links.parser.take_analysis()
+    }
+
+    fn content_hash(html: &str, chunk_size: usize) -> String {
+        analyze(html, chunk_size).content_hash
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let html = "<html><head><title>Home</title><style>p { color: red }</style></head>\
+                    <body><p>Hello,\n   world</p><script>var now = 1;</script></body></html>";
+        let hash = content_hash(html, CHUNK_SIZE);
+        // however the text arrives and whatever whitespace and markup surrounds it
+        assert_eq!(content_hash(html, 3), hash);
+        assert_eq!(
+            content_hash(
+                "<title>Home</title> <div class=\"x\">Hello, world</div>",
+                CHUNK_SIZE
+            ),
+            hash
+        );
+        // scripts and styles don't count
+        assert_eq!(
+            content_hash(&html.replace("now = 1", "now = 2"), CHUNK_SIZE),
+            hash
+        );
+        assert_eq!(content_hash(&html.replace("red", "blue"), CHUNK_SIZE), hash);
+        // but the text does
+        assert_ne!(
+            content_hash(&html.replace("world", "there"), CHUNK_SIZE),
+            hash
+        );
+        assert_ne!(
+            content_hash(&html.replace("Home", "About"), CHUNK_SIZE),
+            hash
+        );
+    }
+
+    #[test]
+    fn test_anchors() {
+        let html = r#"
+            <h2 id="intro">Intro</h2>
+            <a name="old-style">Old</a>
+            <div id="café"><a href="/x" id="link">X</a></div>
+            <img name="not-an-anchor">
+        "#;
+        let analysis = analyze(html, CHUNK_SIZE);
+        let mut anchors: Vec<String> = analysis.anchors.into_iter().collect();
+        anchors.sort();
+        assert_eq!(anchors, vec!["café", "intro", "link", "old-style"]);
+    }
+
+    #[test]
+    fn test_links_in_nav() {
+        let html = r#"
+            <nav><ul><li><a href="/docs">Docs</a></li><nav><a href="/sub">Sub</a></nav>
+            <li><a href="/blog">Blog</a></li></ul></nav>
+            <main><a href="/post">Post</a></main>
+        "#;
+        let links: Vec<(String, bool)> = find_links(html, &[LinkKind::AnchorHref])
+            .map(|link| (link.href, link.in_nav))
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                ("/docs".to_string(), true),
+                ("/sub".to_string(), true),
+                ("/blog".to_string(), true),
+                ("/post".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metadata() {
+        let html = r#"<!DOCTYPE html>
+            <html lang="en-GB"><head>
+            <title>  Home |
+              Example </title>
+            <meta name="Description" content="All about  us">
+            <meta name="description" content="A second description">
+            <meta property="og:title" content="Home">
+            <meta property="OG:Image" content="/cover.png">
+            <meta property="article:author" content="Someone">
+            <link rel="alternate canonical" href="/home">
+            <script type="application/ld+json">
+              {"@type": "Organization"}
+            </script>
+            <script>var words = "not counted";</script>
+            </head><body>
+            <h1>Welcome <em>home</em></h1>
+            <p>Three small words, and more-or-less four.</p>
+            <img src="/hero.png"><img src="/spacer.gif" alt="">
+            <h1></h1>
+            <svg><title>Not the page's title</title></svg>
+            </body></html>"#;
+        let expected = PageMetadata {
+            title: Some("Home | Example".to_string()),
+            description: Some("All about us".to_string()),
+            canonical: Some("/home".to_string()),
+            h1s: vec!["Welcome home".to_string(), String::new()],
+            lang: Some("en-GB".to_string()),
+            word_count: 12,
+            open_graph: vec![
+                ("og:title".to_string(), "Home".to_string()),
+                ("og:image".to_string(), "/cover.png".to_string()),
+            ],
+            json_ld: vec![r#"{"@type": "Organization"}"#.to_string()],
+            images_without_alt: vec!["/hero.png".to_string()],
+        };
+        assert_eq!(analyze(html, CHUNK_SIZE).metadata, expected);
+        // however the document arrives
+        assert_eq!(analyze(html, 3).metadata, expected);
+
+        // scripts and SVG titles inside a heading aren't part of it, and an SVG's title isn't
+        // the page's
+        let html = "<h1>Hello <script>var a;</script>world</h1>\
+                    <h1><svg><title>icon</title></svg>Heading</h1>";
+        let metadata = analyze(html, CHUNK_SIZE).metadata;
+        assert_eq!(metadata.h1s, vec!["Hello world", "Heading"]);
+        assert_eq!(metadata.title, None);
+
+        // an unclosed heading ends with the document
+        let metadata = analyze("<h1>Never closed", CHUNK_SIZE).metadata;
+        assert_eq!(metadata.h1s, vec!["Never closed"]);
+        assert_eq!(metadata.title, None);
+    }
+
+    fn find_all_links(html: &str) -> Vec<(LinkKind, String)> {
+        let link_kinds = [
+            LinkKind::AnchorHref,
+            LinkKind::AreaHref,
+            LinkKind::LinkHref,
+            LinkKind::IframeSrc,
+            LinkKind::FrameSrc,
+            LinkKind::ImgSrc,
+            LinkKind::ImgSrcset,
+            LinkKind::ScriptSrc,
+            LinkKind::SourceSrc,
+            LinkKind::SourceSrcset,
+            LinkKind::FormAction,
+            LinkKind::MetaRefresh,
+            LinkKind::ObjectData,
+        ];
+        find_links(html, &link_kinds)
+            .map(|link| (link.kind, link.href))
+            .collect()
+    }
+
+    #[test]
+    fn test_all_link_kinds() {
+        let html = r#"
+            <head>
+                <meta http-equiv="Refresh" content="5; URL='/next'">
+                <link rel="stylesheet" href="/style.css">
+                <script src="/app.js"></script>
+            </head>
+            <a href="/page">Page</a>
+            <map><area href="/area" shape="rect"></map>
+            <iframe src="/frame.html"></iframe>
+            <frameset><frame src="/old-frame.html"></frameset>
+            <img src="/a.png" srcset="/a-1x.png 1x, /a-2x.png 2x">
+            <picture><source src="/v.mp4" srcset="/s.webp"></picture>
+            <form action="/search"></form>
+            <object data="/movie.swf"></object>
+        "#;
+        assert_eq!(
+            find_all_links(html),
+            vec![
+                (LinkKind::MetaRefresh, "/next".to_string()),
+                (LinkKind::LinkHref, "/style.css".to_string()),
+                (LinkKind::ScriptSrc, "/app.js".to_string()),
+                (LinkKind::AnchorHref, "/page".to_string()),
+                (LinkKind::AreaHref, "/area".to_string()),
+                (LinkKind::IframeSrc, "/frame.html".to_string()),
+                (LinkKind::FrameSrc, "/old-frame.html".to_string()),
+                (LinkKind::ImgSrc, "/a.png".to_string()),
+                (LinkKind::ImgSrcset, "/a-1x.png".to_string()),
+                (LinkKind::ImgSrcset, "/a-2x.png".to_string()),
+                (LinkKind::SourceSrc, "/v.mp4".to_string()),
+                (LinkKind::SourceSrcset, "/s.webp".to_string()),
+                (LinkKind::FormAction, "/search".to_string()),
+                (LinkKind::ObjectData, "/movie.swf".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_only_requested_link_kinds() {
+        let html = r#"
+            <a href="/page">Page</a>
+            <img src="/a.png">
+            <iframe src="/frame.html"></iframe>
+        "#;
+        let links: Vec<(LinkKind, String)> =
+            find_links(html, &[LinkKind::ImgSrc, LinkKind::IframeSrc])
+                .map(|link| (link.kind, link.href))
+                .collect();
+        assert_eq!(
+            links,
+            vec![
+                (LinkKind::ImgSrc, "/a.png".to_string()),
+                (LinkKind::IframeSrc, "/frame.html".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_srcset() {
+        assert_eq!(parse_srcset("a.png"), vec!["a.png"]);
+        assert_eq!(
+            parse_srcset(" a.png 1x,b.png 2x , c.png 480w"),
+            vec!["a.png", "b.png", "c.png"]
+        );
+        assert_eq!(parse_srcset("a.png,b.png"), vec!["a.png,b.png"]);
+        assert_eq!(parse_srcset("a.png, b.png"), vec!["a.png", "b.png"]);
+        assert_eq!(
+            parse_srcset("a.png calc(1, 2), b.png"),
+            vec!["a.png", "b.png"]
+        );
+        assert!(parse_srcset(" , ").is_empty());
+    }
+
+    #[test]
+    fn test_parse_meta_refresh() {
+        assert_eq!(parse_meta_refresh("0; url=/next"), Some("/next"));
+        assert_eq!(parse_meta_refresh("5;URL = '/quoted' "), Some("/quoted"));
+        assert_eq!(parse_meta_refresh("1.5, \"/double\""), Some("/double"));
+        assert_eq!(parse_meta_refresh("3; /bare"), Some("/bare"));
+        assert_eq!(parse_meta_refresh("30"), None);
+        assert_eq!(parse_meta_refresh("0; url="), None);
+    }
+
+    #[test]
+    fn test_base_href() {
+        let html = r#"
+            <html><head>
+                <link rel="stylesheet" href="style.css">
+                <base href="https://example.com/docs/">
+                <base href="https://ignored.com/">
+            </head>
+            <body><a href="intro">Intro</a></body>
+        "#;
+        let mut links = find_links(html, &[LinkKind::AnchorHref, LinkKind::LinkHref]);
+        let first = links.next().unwrap();
+        assert_eq!(first.href, "style.css");
+        // the head is done by the time its first link is yielded, so the base is known
+        assert_eq!(links.base_href(), Some("https://example.com/docs/"));
+        assert_eq!(links.next().unwrap().href, "intro");
+        assert!(links.next().is_none());
+    }
+
+    #[test]
+    fn test_head_links_wait_for_base() {
+        // a long head, so the base is only tokenized after the link's chunk has been fed
+        let html = format!(
+            r#"<head><title>Title text</title><link href="a.css"><!-- {} --><base href="/b/"></head>"#,
+            "x".repeat(CHUNK_SIZE * 2)
+        );
+        let mut links = find_links(&html, &[LinkKind::LinkHref]);
+        assert_eq!(links.next().unwrap().href, "a.css");
+        assert_eq!(links.base_href(), Some("/b/"));
+    }
+
+    #[test]
+    fn test_rel_nofollow() {
+        let html = r#"
+            <a href="/followed" rel="noopener">Followed</a>
+            <a href="/sponsored" REL="sponsored nofollow">Sponsored</a>
+        "#;
+        let links: Vec<(String, bool)> = find_links(html, &[LinkKind::AnchorHref])
+            .map(|link| (link.href.clone(), link.is_nofollow()))
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                ("/followed".to_string(), false),
+                ("/sponsored".to_string(), true)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_meta_robots() {
+        let html = r#"
+            <head>
+                <meta name="googlebot" content="noindex">
+                <meta name="Robots" content="nofollow">
+            </head>
+            <a href="/page">Page</a>
+        "#;
+        let mut links = find_links(html, &[LinkKind::AnchorHref]);
+        assert!(links.next().is_some());
+        let robots = links.robots();
+        assert!(robots.nofollow);
+        assert!(!robots.noindex);
+    }
+
+    #[test]
+    fn test_link_text_and_attributes() {
+        let html = r#"<p>
+            <a href="/pricing" title="Plans" rel="noopener" hreflang="en" target="_blank">
+                Our   <b>pricing</b>
+            </a>
+            <a href="/home"><img src="/logo.png" alt="Home"></a>
+            <a href="/empty"></a>
+            <map><area href="/area" alt="Area"></map>
+            <img src="/plain.png">
+        </p>"#;
+        let link_kinds = [LinkKind::AnchorHref, LinkKind::AreaHref, LinkKind::ImgSrc];
+        let links: Vec<Link> = find_links(html, &link_kinds).collect();
+        let edges: Vec<(&str, Option<&str>, u64)> = links
+            .iter()
+            .map(|link| (link.href.as_str(), link.text.as_deref(), link.line))
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                ("/pricing", Some("Our pricing"), 2),
+                ("/home", Some("Home"), 5),
+                ("/logo.png", None, 5),
+                ("/empty", Some(""), 6),
+                ("/area", Some("Area"), 7),
+                ("/plain.png", None, 8),
+            ]
+        );
+
+        let edge = links[0].edge(Some("monthly".to_string()));
+        assert_eq!(edge.fragment.as_deref(), Some("monthly"));
+        assert_eq!(edge.title.as_deref(), Some("Plans"));
+        assert_eq!(edge.rel.as_deref(), Some("noopener"));
+        assert_eq!(edge.hreflang.as_deref(), Some("en"));
+        assert_eq!(edge.target.as_deref(), Some("_blank"));
+        assert_eq!(links[1].title, None);
+    }
+
+    #[test]
+    fn test_link_text_ends_at_next_anchor_or_eof() {
+        let html = r#"
+            <li><a href="/one">One
+            <li><a href="/two">Two</a> after
+            <li><a href="/three">Three
+        "#;
+        let texts: Vec<Option<String>> = find_links(html, &[LinkKind::AnchorHref])
+            .map(|link| link.text)
+            .collect();
+        assert_eq!(
+            texts,
+            vec![
+                Some("One".to_string()),
+                Some("Two".to_string()),
+                Some("Three".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_long_link_text_is_truncated() {
+        let html = format!(r#"<a href="/long">{}</a>"#, "é".repeat(MAX_LINK_TEXT_LEN));
+        let link = find_links(&html, &[LinkKind::AnchorHref]).next().unwrap();
+        let text = link.text.unwrap();
+        assert!(text.len() <= MAX_LINK_TEXT_LEN);
+        assert!(text.starts_with("éé"));
+    }
+
+    #[test]
+    fn test_byte_at_a_time() {
+        let html = "<p>caf\u{e9} <a href=\"/caf\u{e9}\" title=\"\u{1f600}\">\u{e9}t\u{e9}</a></p>";
+        let links = Links {
+            parser: LinkParser::new(&[LinkKind::AnchorHref], Some(UTF_8)),
+            html: html.as_bytes(),
+            chunk_size: 1,
+        };
+        let links: Vec<Link> = links.collect();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].href, "/café");
+        assert_eq!(links[0].title.as_deref(), Some("\u{1f600}"));
+        assert_eq!(links[0].text.as_deref(), Some("été"));
+    }
+
+    #[test]
+    fn test_declared_encoding() {
+        let links = Links {
+            parser: LinkParser::new(&[LinkKind::AnchorHref], Some(WINDOWS_1252)),
+            html: b"<a href=\"/caf\xe9\">Caf\xe9</a>",
+            chunk_size: 3,
+        };
+        let links: Vec<(String, Option<String>)> =
+            links.map(|link| (link.href, link.text)).collect();
+        assert_eq!(links, vec![("/café".to_string(), Some("Café".to_string()))]);
+    }
+
+    #[test]
+    fn test_links_yielded_before_the_document_ends() {
+        let mut parser = LinkParser::new(&[LinkKind::AnchorHref], Some(UTF_8));
+        parser.push(b"<body><a href=\"/first\">First</a><a hr");
+        assert_eq!(parser.next_link().unwrap().href, "/first");
+        assert!(parser.next_link().is_none());
+        parser.push(b"ef=\"/second\">Sec");
+        // its text could still go on
+        assert!(parser.next_link().is_none());
+        parser.push(b"ond</a>");
+        assert_eq!(parser.next_link().unwrap().text.as_deref(), Some("Second"));
+        parser.finish();
+        assert!(parser.next_link().is_none());
+    }
+
+    fn sniffed_links<'a>(html: &'a [u8], header_encoding: Option<&'static Encoding>) -> Links<'a> {
+        Links {
+            parser: LinkParser::new(&[LinkKind::AnchorHref], header_encoding),
+            html,
+            chunk_size: 5,
+        }
+    }
+
+    #[test]
+    fn test_meta_charset_is_sniffed() {
+        let html = b"<head><meta charset=\"shift_jis\"></head><a href=\"/\x93\xfa\x96\x7b\">\x93\xfa\x96\x7b</a>";
+        let mut links = sniffed_links(html, None);
+        let link = links.next().unwrap();
+        assert_eq!(link.href, "/日本");
+        assert_eq!(link.text.as_deref(), Some("日本"));
+        assert_eq!(links.parser.encoding(), SHIFT_JIS);
+
+        let html = b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=windows-1252\"><a href=\"/caf\xe9\">";
+        let link = sniffed_links(html, None).next().unwrap();
+        assert_eq!(link.href, "/café");
+    }
+
+    #[test]
+    fn test_header_encoding_wins_over_meta() {
+        let html = b"<meta charset=\"shift_jis\"><a href=\"/caf\xe9\">";
+        let mut links = sniffed_links(html, Some(WINDOWS_1252));
+        assert_eq!(links.next().unwrap().href, "/café");
+        assert_eq!(links.parser.encoding(), WINDOWS_1252);
+    }
+
+    #[test]
+    fn test_byte_order_mark_wins() {
+        let html = b"\xef\xbb\xbf<meta charset=\"windows-1252\"><a href=\"/caf\xc3\xa9\">";
+        let mut links = sniffed_links(html, Some(WINDOWS_1252));
+        assert_eq!(links.next().unwrap().href, "/café");
+        assert_eq!(links.parser.encoding(), UTF_8);
+    }
+
+    #[test]
+    fn test_undeclared_encoding_is_utf8() {
+        let html = "<a href=\"/café\">Café</a>";
+        let mut links = sniffed_links(html.as_bytes(), None);
+        assert_eq!(links.next().unwrap().href, "/café");
+        assert_eq!(links.parser.encoding(), UTF_8);
+    }
+
+    // Run with `cargo test --release -p service -- --ignored bench_single_pass --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_single_pass() {
+        use std::time::Instant;
+
+        let mut html = String::from("<html><head><title>Benchmark</title></head><body>");
+        for i in 0..100_000 {
+            html.push_str(&format!(
+                r#"<div class="item"><p>Paragraph {} with <b>some</b> text.</p><a href="/page/{}" title="Page">Page {}</a><img src="/img/{}.png" alt=""></div>"#,
+                i, i, i, i
+            ));
+        }
+        html.push_str("</body></html>");
+        let link_kinds = [LinkKind::AnchorHref, LinkKind::ImgSrc];
+        let megabytes = html.len() as f64 / (1024.0 * 1024.0);
+
+        // one tokenizer pass over the page
+        let start = Instant::now();
+        let count = find_links(&html, &link_kinds).count();
+        let single_pass = start.elapsed();
+        assert_eq!(count, 200_000);
+
+        // what four workers each copying and tokenizing the whole page used to cost
+        let start = Instant::now();
+        for _ in 0..4 {
+            let document = html.clone();
+            assert_eq!(find_links(&document, &link_kinds).count(), 200_000);
+        }
+        let four_passes = start.elapsed();
+
+        println!(
+            "{:.1} MiB page: single pass {:?} ({:.1} MiB/s), four passes {:?} ({:.1} MiB/s)",
+            megabytes,
+            single_pass,
+            megabytes / single_pass.as_secs_f64(),
+            four_passes,
+            megabytes / four_passes.as_secs_f64(),
+        );
     }
 }
@@ -0,0 +1,472 @@
+use chrono::{
+    DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike,
+};
+use serde::{Deserialize, Serialize};
+
+use shared::RecrawlPolicy;
+
+use crate::{error::CrawlerError, fetcher::Validators};
+
+// Longer intervals are cut down to this, about a century
+const MAX_INTERVAL_SECS: u64 = 100 * 365 * 24 * 60 * 60;
+
+// How far ahead a cron schedule is searched for its next run, so impossible dates like
+// `0 0 31 2 *` don't search forever
+const MAX_SCHEDULE_SEARCH_DAYS: i64 = 5 * 366;
+
+// Decides when a site's pages are fetched again
+#[derive(Clone)]
+pub(crate) struct Recrawl {
+    schedule: RecrawlSchedule,
+    // pages fetched before the current `start` are fetched again, whatever the policy
+    run_started_at: DateTime<Local>,
+}
+
+#[derive(Clone)]
+enum RecrawlSchedule {
+    Never,
+    Interval(Duration),
+    Cron(CronSchedule),
+    Adaptive { min: Duration, max: Duration },
+}
+
+impl Recrawl {
+    pub(crate) fn new(
+        policy: &RecrawlPolicy,
+        run_started_at: DateTime<Local>,
+    ) -> Result<Self, CrawlerError> {
+        let seconds = |seconds: u64| Duration::seconds(seconds.clamp(1, MAX_INTERVAL_SECS) as i64);
+        let schedule = match policy {
+            RecrawlPolicy::Never => RecrawlSchedule::Never,
+            RecrawlPolicy::Interval(interval_secs) => {
+                RecrawlSchedule::Interval(seconds(*interval_secs))
+            }
+            RecrawlPolicy::Cron(expression) => {
+                RecrawlSchedule::Cron(CronSchedule::new(expression)?)
+            }
+            RecrawlPolicy::Adaptive { min_secs, max_secs } => {
+                if min_secs > max_secs {
+                    return Err(CrawlerError::InvalidRecrawlPolicy(
+                        format!("{}..{}", min_secs, max_secs),
+                        "the minimum interval is longer than the maximum".to_string(),
+                    ));
+                }
+                RecrawlSchedule::Adaptive {
+                    min: seconds(*min_secs),
+                    max: seconds(*max_secs),
+                }
+            }
+        };
+        Ok(Self {
+            schedule,
+            run_started_at,
+        })
+    }
+
+    // Whether the page should be fetched now
+    pub(crate) fn is_due(&self, history: &FetchHistory, now: DateTime<Local>) -> bool {
+        match history.last_fetched_at {
+            None => true,
+            Some(fetched_at) if fetched_at < self.run_started_at => true,
+            Some(_) => self.next_due(history).is_some_and(|due| due <= now),
+        }
+    }
+
+    // Whether the policy has a page fetched during the current run fetched again now. The ones
+    // that weren't are left for the run to reach.
+    pub(crate) fn is_recrawl_due(&self, history: &FetchHistory, now: DateTime<Local>) -> bool {
+        self.fetched_this_run(history) && self.next_due(history).is_some_and(|due| due <= now)
+    }
+
+    pub(crate) fn fetched_this_run(&self, history: &FetchHistory) -> bool {
+        history
+            .last_fetched_at
+            .is_some_and(|fetched_at| fetched_at >= self.run_started_at)
+    }
+
+    // When the policy has the page fetched again, or `None` if it never is. A failed fetch
+    // waits as long as a successful one.
+    pub(crate) fn next_due(&self, history: &FetchHistory) -> Option<DateTime<Local>> {
+        let fetched_at = history.last_fetched_at.max(history.last_attempted_at)?;
+        match &self.schedule {
+            RecrawlSchedule::Never => None,
+            RecrawlSchedule::Interval(interval) => Some(fetched_at + *interval),
+            RecrawlSchedule::Cron(cron_schedule) => cron_schedule.next_after(fetched_at),
+            RecrawlSchedule::Adaptive { min, max } => {
+                let interval = history
+                    .adaptive_interval_secs
+                    .map_or(*min, |interval_secs| {
+                        Duration::seconds(interval_secs as i64)
+                    });
+                Some(fetched_at + interval.clamp(*min, *max))
+            }
+        }
+    }
+
+    pub(crate) fn run_started_at(&self) -> DateTime<Local> {
+        self.run_started_at
+    }
+}
+
+// What's known about the previous fetches of a page
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub(crate) struct FetchHistory {
+    pub(crate) last_fetched_at: Option<DateTime<Local>>,
+    // including the fetches that failed
+    last_attempted_at: Option<DateTime<Local>>,
+    // of the last HTML page's text, to tell whether the page changed since
+    content_hash: Option<String>,
+    // how long the adaptive policy waits before fetching the page again
+    adaptive_interval_secs: Option<u64>,
+    // from the last response whose links were all recorded
+    validators: Validators,
+}
+
+impl FetchHistory {
+    // Records a fetch, lengthening the adaptive interval when the page was unchanged, and
+    // shortening it when it changed
+    pub(crate) fn record(
+        &mut self,
+        fetched_at: DateTime<Local>,
+        content_hash: Option<String>,
+        validators: Validators,
+        recrawl: &Recrawl,
+    ) {
+        if let RecrawlSchedule::Adaptive { min, max } = recrawl.schedule {
+            let interval = match (&self.content_hash, &content_hash) {
+                (Some(previous), Some(current)) => {
+                    let interval = self
+                        .adaptive_interval_secs
+                        .map_or(min, |interval_secs| Duration::seconds(interval_secs as i64));
+                    if previous == current {
+                        interval * 2
+                    } else {
+                        interval / 2
+                    }
+                }
+                // nothing to compare yet
+                _ => min,
+            };
+            self.adaptive_interval_secs = Some(interval.clamp(min, max).num_seconds() as u64);
+        }
+        self.last_fetched_at = Some(fetched_at);
+        self.last_attempted_at = Some(fetched_at);
+        self.content_hash = content_hash;
+        self.validators = validators;
+    }
+
+    // Records a `304 Not Modified`, which may come with updated validators
+    pub(crate) fn record_not_modified(
+        &mut self,
+        fetched_at: DateTime<Local>,
+        validators: Validators,
+        recrawl: &Recrawl,
+    ) {
+        let validators = if validators.is_empty() {
+            self.validators.clone()
+        } else {
+            validators
+        };
+        self.record(fetched_at, self.content_hash.clone(), validators, recrawl);
+    }
+
+    // Records a fetch that got no response, leaving what's known about the page as it was
+    pub(crate) fn record_failure(&mut self, attempted_at: DateTime<Local>) {
+        self.last_attempted_at = Some(attempted_at);
+    }
+
+    pub(crate) fn validators(&self) -> &Validators {
+        &self.validators
+    }
+}
+
+// A cron expression: `minute hour day-of-month month day-of-week`, where each field is `*` or
+// a list of values and ranges, optionally with a `/step`
+#[derive(Clone)]
+struct CronSchedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days_of_month: Vec<bool>,
+    months: Vec<bool>,
+    days_of_week: Vec<bool>,
+    // as in cron, a day matches either day field when both are restricted
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+impl CronSchedule {
+    fn new(expression: &str) -> Result<Self, CrawlerError> {
+        let invalid = |reason: &str| {
+            CrawlerError::InvalidRecrawlPolicy(expression.to_string(), reason.to_string())
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            return Err(invalid("expected 5 fields: minute hour day month weekday"));
+        };
+
+        let mut days_of_week = parse_cron_field(days_of_week, 0, 7).map_err(|err| invalid(&err))?;
+        // both 0 and 7 are Sunday
+        if days_of_week[7] {
+            days_of_week[0] = true;
+        }
+        Ok(Self {
+            minutes: parse_cron_field(minutes, 0, 59).map_err(|err| invalid(&err))?,
+            hours: parse_cron_field(hours, 0, 23).map_err(|err| invalid(&err))?,
+            days_of_month: parse_cron_field(days_of_month, 1, 31).map_err(|err| invalid(&err))?,
+            months: parse_cron_field(months, 1, 12).map_err(|err| invalid(&err))?,
+            days_of_week,
+            any_day_of_month: days_of_month.starts_with('*'),
+            any_day_of_week: fields[4].starts_with('*'),
+        })
+    }
+
+    // The first minute after `time` the schedule matches
+    fn next_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = time.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let mut date = start.date();
+        let mut first_minute_of_day = Some(start.time());
+
+        for _ in 0..MAX_SCHEDULE_SEARCH_DAYS {
+            if self.matches_date(date) {
+                let from = first_minute_of_day.unwrap_or(NaiveTime::MIN);
+                for minute_of_day in (from.hour() * 60 + from.minute())..24 * 60 {
+                    let (hour, minute) = (minute_of_day / 60, minute_of_day % 60);
+                    if !self.hours[hour as usize] || !self.minutes[minute as usize] {
+                        continue;
+                    }
+                    let naive = NaiveDateTime::new(date, NaiveTime::from_hms_opt(hour, minute, 0)?);
+                    // times skipped by a daylight saving change never happen
+                    if let Some(scheduled) = Local.from_local_datetime(&naive).earliest() {
+                        return Some(scheduled);
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+            first_minute_of_day = None;
+        }
+        None
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        if !self.months[date.month() as usize] {
+            return false;
+        }
+        let day_of_month = self.days_of_month[date.day() as usize];
+        let day_of_week = self.days_of_week[date.weekday().num_days_from_sunday() as usize];
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        }
+    }
+}
+
+// Which values from 0 to `max` the field matches
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>, String> {
+    let mut matches = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .map_err(|_| format!("invalid step in `{}`", part))?;
+                if step == 0 {
+                    return Err(format!("invalid step in `{}`", part));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (
+                parse_cron_value(start, min, max)?,
+                parse_cron_value(end, min, max)?,
+            )
+        } else {
+            let value = parse_cron_value(range, min, max)?;
+            // `5/15` means from 5 to the end, every 15
+            (value, if step > 1 { max } else { value })
+        };
+        if start > end {
+            return Err(format!("invalid range `{}`", range));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            matches[value as usize] = true;
+        }
+    }
+    Ok(matches)
+}
+
+fn parse_cron_value(value: &str, min: u32, max: u32) -> Result<u32, String> {
+    match value.parse() {
+        Ok(value) if (min..=max).contains(&value) => Ok(value),
+        _ => Err(format!("`{}` is not between {} and {}", value, min, max)),
+    }
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        let naive = NaiveDate::from_ymd_opt(year, month, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap();
+        Local.from_local_datetime(&naive).earliest().unwrap()
+    }
+
+    fn next_after(expression: &str, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        CronSchedule::new(expression).unwrap().next_after(time)
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        let time = local(2024, 3, 14, 10, 30);
+        assert_eq!(
+            next_after("* * * * *", time),
+            Some(local(2024, 3, 14, 10, 31))
+        );
+        assert_eq!(
+            next_after("*/15 * * * *", time),
+            Some(local(2024, 3, 14, 10, 45))
+        );
+        assert_eq!(
+            next_after("0 3 * * *", time),
+            Some(local(2024, 3, 15, 3, 0))
+        );
+        assert_eq!(
+            next_after("30 10 * * *", time),
+            Some(local(2024, 3, 15, 10, 30))
+        );
+        assert_eq!(
+            next_after("0 9-17/4 * * *", time),
+            Some(local(2024, 3, 14, 13, 0))
+        );
+        assert_eq!(
+            next_after("0 0 1 1,7 *", time),
+            Some(local(2024, 7, 1, 0, 0))
+        );
+        // 2024-03-14 is a Thursday
+        assert_eq!(
+            next_after("0 12 * * 0", time),
+            Some(local(2024, 3, 17, 12, 0))
+        );
+        assert_eq!(
+            next_after("0 12 * * 7", time),
+            Some(local(2024, 3, 17, 12, 0))
+        );
+        assert_eq!(
+            next_after("0 0 29 2 *", time),
+            Some(local(2028, 2, 29, 0, 0))
+        );
+        assert_eq!(next_after("0 0 31 2 *", time), None);
+    }
+
+    #[test]
+    fn test_cron_day_fields() {
+        let time = local(2024, 3, 14, 10, 30);
+        // restricting both day fields matches either
+        assert_eq!(
+            next_after("0 0 20 * 5", time),
+            Some(local(2024, 3, 15, 0, 0))
+        );
+        // with one of them `*`, only the other one counts
+        assert_eq!(
+            next_after("0 0 20 * *", time),
+            Some(local(2024, 3, 20, 0, 0))
+        );
+        assert_eq!(
+            next_after("0 0 * * 5", time),
+            Some(local(2024, 3, 15, 0, 0))
+        );
+    }
+
+    #[test]
+    fn test_invalid_cron() {
+        for expression in [
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+        ] {
+            assert!(CronSchedule::new(expression).is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn test_due() {
+        let run_started_at = local(2024, 3, 14, 9, 0);
+        let fetched_at = local(2024, 3, 14, 10, 0);
+        let history = FetchHistory {
+            last_fetched_at: Some(fetched_at),
+            ..FetchHistory::default()
+        };
+        let recrawl = |policy| Recrawl::new(&policy, run_started_at).unwrap();
+
+        let never = recrawl(RecrawlPolicy::Never);
+        assert!(never.is_due(&FetchHistory::default(), fetched_at));
+        assert!(!never.is_due(&history, local(2030, 1, 1, 0, 0)));
+        let hourly = recrawl(RecrawlPolicy::Interval(3600));
+        assert!(!hourly.is_due(&history, local(2024, 3, 14, 10, 59)));
+        assert!(hourly.is_due(&history, local(2024, 3, 14, 11, 0)));
+
+        // everything is fetched again once a new run starts
+        let restarted = Recrawl::new(&RecrawlPolicy::Never, local(2024, 3, 15, 0, 0)).unwrap();
+        assert!(restarted.is_due(&history, local(2024, 3, 15, 0, 0)));
+    }
+
+    #[test]
+    fn test_failed_fetch_waits_for_the_interval() {
+        let recrawl =
+            Recrawl::new(&RecrawlPolicy::Interval(3600), local(2024, 3, 14, 9, 0)).unwrap();
+        let mut history = FetchHistory::default();
+        history.record(
+            local(2024, 3, 14, 10, 0),
+            None,
+            Validators::default(),
+            &recrawl,
+        );
+        history.record_failure(local(2024, 3, 14, 11, 0));
+
+        assert!(!recrawl.is_recrawl_due(&history, local(2024, 3, 14, 11, 30)));
+        assert!(recrawl.is_recrawl_due(&history, local(2024, 3, 14, 12, 0)));
+        assert_eq!(history.last_fetched_at, Some(local(2024, 3, 14, 10, 0)));
+    }
+
+    #[test]
+    fn test_adaptive_interval() {
+        let policy = RecrawlPolicy::Adaptive {
+            min_secs: 60,
+            max_secs: 300,
+        };
+        let recrawl = Recrawl::new(&policy, local(2024, 3, 14, 9, 0)).unwrap();
+        let mut history = FetchHistory::default();
+        let mut fetched_at = local(2024, 3, 14, 10, 0);
+        let mut interval_after = |history: &mut FetchHistory, content_hash: &str| {
+            history.record(
+                fetched_at,
+                Some(content_hash.to_string()),
+                Validators::default(),
+                &recrawl,
+            );
+            let interval = recrawl.next_due(history).unwrap() - fetched_at;
+            fetched_at += interval;
+            interval.num_seconds()
+        };
+
+        assert_eq!(interval_after(&mut history, "1"), 60);
+        // unchanged pages wait longer each time, up to the maximum
+        assert_eq!(interval_after(&mut history, "1"), 120);
+        assert_eq!(interval_after(&mut history, "1"), 240);
+        assert_eq!(interval_after(&mut history, "1"), 300);
+        // and changed ones sooner
+        assert_eq!(interval_after(&mut history, "2"), 150);
+        assert_eq!(interval_after(&mut history, "3"), 75);
+        assert_eq!(interval_after(&mut history, "4"), 60);
+    }
+}
@@ -0,0 +1,112 @@
+// Indexing directives from `<meta name="robots">` or the `X-Robots-Tag` header
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct RobotsDirectives {
+    pub(crate) noindex: bool,
+    pub(crate) nofollow: bool,
+}
+
+impl RobotsDirectives {
+    // Parses a comma separated list of directives, e.g. `noindex, nofollow`
+    pub(crate) fn parse(content: &str) -> Self {
+        let mut directives = Self::default();
+        for directive in content.split(',') {
+            directives.apply(directive.trim());
+        }
+        directives
+    }
+
+    // Parses `X-Robots-Tag` header values, skipping directives aimed at a specific crawler,
+    // e.g. `googlebot: noindex`
+    pub(crate) fn parse_header<'a>(values: impl Iterator<Item = &'a str>) -> Self {
+        let mut directives = Self::default();
+        for value in values {
+            if let Some((user_agent, _)) = value.split_once(':') {
+                if !is_directive(user_agent.trim()) {
+                    continue;
+                }
+            }
+            directives.merge(Self::parse(value));
+        }
+        directives
+    }
+
+    pub(crate) fn merge(&mut self, other: Self) {
+        self.noindex |= other.noindex;
+        self.nofollow |= other.nofollow;
+    }
+
+    fn apply(&mut self, directive: &str) {
+        if directive.eq_ignore_ascii_case("noindex") {
+            self.noindex = true;
+        } else if directive.eq_ignore_ascii_case("nofollow") {
+            self.nofollow = true;
+        } else if directive.eq_ignore_ascii_case("none") {
+            self.noindex = true;
+            self.nofollow = true;
+        }
+    }
+}
+
+// Directives that take a value after a colon, rather than being prefixed by a user agent
+fn is_directive(name: &str) -> bool {
+    [
+        "unavailable_after",
+        "max-snippet",
+        "max-image-preview",
+        "max-video-preview",
+    ]
+    .iter()
+    .any(|directive| directive.eq_ignore_ascii_case(name))
+}
+
+// Whether a `rel` attribute contains the `nofollow` keyword
+pub(crate) fn is_rel_nofollow(rel: &str) -> bool {
+    rel.split_ascii_whitespace()
+        .any(|keyword| keyword.eq_ignore_ascii_case("nofollow"))
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_meta_content() {
+        let directives = RobotsDirectives::parse("NoIndex, follow");
+        assert!(directives.noindex);
+        assert!(!directives.nofollow);
+
+        let directives = RobotsDirectives::parse("none");
+        assert!(directives.noindex && directives.nofollow);
+
+        assert_eq!(
+            RobotsDirectives::parse("index,follow"),
+            RobotsDirectives::default()
+        );
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let directives = RobotsDirectives::parse_header(["nofollow"].into_iter());
+        assert!(directives.nofollow);
+        assert!(!directives.noindex);
+
+        // directives for other crawlers are skipped
+        let directives = RobotsDirectives::parse_header(["googlebot: noindex"].into_iter());
+        assert_eq!(directives, RobotsDirectives::default());
+
+        let directives = RobotsDirectives::parse_header(
+            ["unavailable_after: 25 Jun 2010 15:00:00 PST, noindex"].into_iter(),
+        );
+        assert!(directives.noindex);
+    }
+
+    #[test]
+    fn test_rel_nofollow() {
+        assert!(is_rel_nofollow("nofollow"));
+        assert!(is_rel_nofollow("noopener NoFollow noreferrer"));
+        assert!(!is_rel_nofollow("nofollower"));
+        assert!(!is_rel_nofollow(""));
+    }
+}
@@ -0,0 +1,511 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Write,
+};
+
+use chrono::{DateTime, Local};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use shared::{BrokenLink, Referrer};
+
+use crate::{
+    crawler::{strip_fragment, strip_url_to_domain_and_path},
+    store::PageLink,
+};
+
+// How many of a site's runs are kept to compare, oldest dropped first
+const MAX_SAVED_RUNS: usize = 10;
+
+// What a crawl found between one `start` of a site and the next
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct CrawlRun {
+    pub(crate) id: u32,
+    pub(crate) started_at: DateTime<Local>,
+    // the URL the run was started from
+    pub(crate) start_url: Url,
+    // the last fetch of each page during the run
+    pub(crate) pages: HashMap<Url, RunPage>,
+    // the URLs in the crawl's scope that the site's sitemaps listed when the run started
+    pub(crate) sitemap_urls: Vec<Url>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct RunPage {
+    pub(crate) status: PageStatus,
+    // of the page's text, for HTML pages that were read in full
+    pub(crate) content_hash: Option<String>,
+    pub(crate) outlinks: Vec<Url>,
+    // the ids and anchor names that links to the page can have as their `#fragment`
+    pub(crate) anchors: HashSet<String>,
+    // only the start of the body was read, as the rest was over the size limit
+    pub(crate) truncated: bool,
+}
+
+impl RunPage {
+    // A page whose body wasn't parsed, such as one that isn't HTML or couldn't be fetched
+    pub(crate) fn unparsed(status: PageStatus) -> Self {
+        Self {
+            status,
+            content_hash: None,
+            outlinks: Vec::new(),
+            anchors: HashSet::new(),
+            truncated: false,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum PageStatus {
+    Http(u16),
+    // no response at all, e.g. the connection failed
+    Failed(String),
+    // no response in time
+    TimedOut,
+}
+
+impl PageStatus {
+    // The status of a page whose fetch failed with the error
+    pub(crate) fn from_error(err: &anyhow::Error) -> Self {
+        let timed_out = err
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|err| err.is_timeout());
+        if timed_out {
+            PageStatus::TimedOut
+        } else {
+            // the innermost cause says what went wrong, e.g. `Connection refused`
+            PageStatus::Failed(err.root_cause().to_string())
+        }
+    }
+
+    pub(crate) fn is_broken(&self) -> bool {
+        match self {
+            PageStatus::Http(status) => *status >= 400,
+            PageStatus::Failed(_) | PageStatus::TimedOut => true,
+        }
+    }
+}
+
+impl std::fmt::Display for PageStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PageStatus::Http(status) => match StatusCode::from_u16(*status)
+                .ok()
+                .and_then(|status| status.canonical_reason())
+            {
+                Some(reason) => write!(f, "{} {}", status, reason),
+                None => write!(f, "{}", status),
+            },
+            PageStatus::Failed(message) => write!(f, "failed: {}", message),
+            PageStatus::TimedOut => write!(f, "timed out"),
+        }
+    }
+}
+
+// Starts a site's next run, numbered on from the last one
+pub(crate) fn start_run(runs: &mut Vec<CrawlRun>, started_at: DateTime<Local>, start_url: Url) {
+    let id = runs.last().map_or(1, |run| run.id + 1);
+    runs.push(CrawlRun {
+        id,
+        started_at,
+        start_url,
+        pages: HashMap::new(),
+        sitemap_urls: Vec::new(),
+    });
+    if runs.len() > MAX_SAVED_RUNS {
+        runs.remove(0);
+    }
+}
+
+// Describes what changed on a site from one run to a later one
+pub(crate) fn diff_report(site: &Url, old_run: &CrawlRun, new_run: &CrawlRun) -> String {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut changed = Vec::new();
+    let mut newly_broken = Vec::new();
+
+    for (url, new_page) in &new_run.pages {
+        let old_page = old_run.pages.get(url);
+        let was_broken = old_page.is_some_and(|old_page| old_page.status.is_broken());
+        if new_page.status.is_broken() && !was_broken {
+            newly_broken.push(format!("{} ({})", url, new_page.status));
+        }
+        match old_page {
+            None => added.push(url.to_string()),
+            Some(old_page) => {
+                if let Some(page_changes) = page_changes(old_page, new_page) {
+                    changed.push(format!("{}{}", url, page_changes));
+                }
+            }
+        }
+    }
+    for url in old_run.pages.keys() {
+        if !new_run.pages.contains_key(url) {
+            removed.push(url.to_string());
+        }
+    }
+
+    let mut report = format!(
+        "Comparing run {} ({}) with run {} ({}) of {}\n",
+        old_run.id,
+        old_run.started_at.format("%Y-%m-%d %H:%M"),
+        new_run.id,
+        new_run.started_at.format("%Y-%m-%d %H:%M"),
+        site
+    );
+    let sections = [
+        ("Added", added),
+        ("Removed", removed),
+        ("Changed", changed),
+        ("Newly broken", newly_broken),
+    ];
+    if sections.iter().all(|(_, entries)| entries.is_empty()) {
+        report.push_str("No differences\n");
+    }
+    for (title, mut entries) in sections {
+        if entries.is_empty() {
+            continue;
+        }
+        entries.sort();
+        let _ = writeln!(report, "{} ({}):", title, entries.len());
+        for entry in entries {
+            let _ = writeln!(report, "  {}", entry);
+        }
+    }
+    report
+}
+
+// The run's broken pages, and the links to fragments that no element of their page has, each
+// with the pages of the run that link to it, sorted by URL
+pub(crate) fn broken_links(
+    run: &CrawlRun,
+    url_links: &HashMap<Url, Vec<PageLink>>,
+) -> Vec<BrokenLink> {
+    let mut broken: BTreeMap<String, BrokenLink> = BTreeMap::new();
+    for (url, page) in &run.pages {
+        if page.status.is_broken() {
+            broken.insert(url.to_string(), broken_link(url, page.status.to_string()));
+        }
+    }
+
+    for page_url in run.pages.keys() {
+        for (link_url, edge) in url_links.get(page_url).into_iter().flatten() {
+            // links to other sites are recorded with their query and trailing slash
+            let target_url = [
+                strip_fragment(link_url.clone()),
+                strip_url_to_domain_and_path(link_url.clone()),
+            ]
+            .into_iter()
+            .find(|target_url| run.pages.contains_key(target_url));
+            let Some(target_url) = target_url else {
+                continue;
+            };
+            let target_page = &run.pages[&target_url];
+            let broken_link = if target_page.status.is_broken() {
+                broken.get_mut(target_url.as_str())
+            } else if is_missing_fragment(target_page, edge.fragment.as_deref()) {
+                let mut fragment_url = target_url;
+                fragment_url.set_fragment(link_url.fragment());
+                let broken_link = broken
+                    .entry(fragment_url.to_string())
+                    .or_insert_with(|| broken_link(&fragment_url, "missing anchor".to_string()));
+                Some(broken_link)
+            } else {
+                None
+            };
+            if let Some(broken_link) = broken_link {
+                let referrer = Referrer {
+                    page_url: page_url.to_string(),
+                    text: edge.text.clone(),
+                };
+                broken_link.referrers.push(referrer);
+            }
+        }
+    }
+
+    let mut broken: Vec<BrokenLink> = broken.into_values().collect();
+    for broken_link in &mut broken {
+        let referrers = &mut broken_link.referrers;
+        referrers.sort_by(|a, b| (&a.page_url, &a.text).cmp(&(&b.page_url, &b.text)));
+        referrers.dedup_by(|a, b| a.page_url == b.page_url && a.text == b.text);
+    }
+    broken
+}
+
+fn broken_link(url: &Url, status: String) -> BrokenLink {
+    BrokenLink {
+        url: url.to_string(),
+        status,
+        referrers: Vec::new(),
+    }
+}
+
+// Whether the fragment names no element of the page. Only pages that were parsed in full are
+// known to lack one, and the empty fragment, `#top` and text fragments (`#:~:text=...`) need
+// no element to scroll to.
+fn is_missing_fragment(page: &RunPage, fragment: Option<&str>) -> bool {
+    let Some(fragment) = fragment else {
+        return false;
+    };
+    let needs_element = !fragment.is_empty()
+        && !fragment.eq_ignore_ascii_case("top")
+        && !fragment.starts_with(":~:");
+    needs_element && page.content_hash.is_some() && !page.anchors.contains(fragment)
+}
+
+// How a page that's in both runs changed, as a summary followed by the links added and
+// removed, or `None` if it didn't
+fn page_changes(old_page: &RunPage, new_page: &RunPage) -> Option<String> {
+    let mut summary = Vec::new();
+    if let (Some(old_hash), Some(new_hash)) = (&old_page.content_hash, &new_page.content_hash) {
+        if old_hash != new_hash {
+            summary.push("content changed".to_string());
+        }
+    }
+    if old_page.status != new_page.status {
+        summary.push(format!("{} -> {}", old_page.status, new_page.status));
+    }
+    let old_links: BTreeSet<&Url> = old_page.outlinks.iter().collect();
+    let new_links: BTreeSet<&Url> = new_page.outlinks.iter().collect();
+    let added_links: Vec<&&Url> = new_links.difference(&old_links).collect();
+    let removed_links: Vec<&&Url> = old_links.difference(&new_links).collect();
+    if !added_links.is_empty() || !removed_links.is_empty() {
+        summary.push(format!(
+            "+{} -{} links",
+            added_links.len(),
+            removed_links.len()
+        ));
+    }
+    if summary.is_empty() {
+        return None;
+    }
+
+    let mut changes = format!(" ({})", summary.join(", "));
+    for link in added_links {
+        let _ = write!(changes, "\n    + {}", link);
+    }
+    for link in removed_links {
+        let _ = write!(changes, "\n    - {}", link);
+    }
+    Some(changes)
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use shared::LinkEdge;
+
+    use crate::test_util::{anchor_edge, url};
+
+    fn page(status: u16, content_hash: &str, outlinks: &[&str]) -> RunPage {
+        RunPage {
+            status: PageStatus::Http(status),
+            content_hash: Some(content_hash.to_string()),
+            outlinks: outlinks.iter().map(|path| url(path)).collect(),
+            anchors: HashSet::new(),
+            truncated: false,
+        }
+    }
+
+    fn edge(text: &str, fragment: Option<&str>) -> LinkEdge {
+        LinkEdge {
+            fragment: fragment.map(str::to_string),
+            ..anchor_edge(text)
+        }
+    }
+
+    fn run(id: u32, pages: Vec<(&str, RunPage)>) -> CrawlRun {
+        CrawlRun {
+            id,
+            started_at: Local::now(),
+            start_url: url("/"),
+            pages: pages
+                .into_iter()
+                .map(|(path, page)| (url(path), page))
+                .collect(),
+            sitemap_urls: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_start_run() {
+        let mut runs = Vec::new();
+        for _ in 0..MAX_SAVED_RUNS + 2 {
+            start_run(&mut runs, Local::now(), url("/"));
+        }
+        assert_eq!(runs.len(), MAX_SAVED_RUNS);
+        assert_eq!(runs.first().unwrap().id, 3);
+        assert_eq!(runs.last().unwrap().id, MAX_SAVED_RUNS as u32 + 2);
+    }
+
+    #[test]
+    fn test_diff_report() {
+        let old_run = run(
+            1,
+            vec![
+                ("/", page(200, "home", &["/a", "/b"])),
+                ("/a", page(200, "a", &[])),
+                ("/b", page(200, "b", &[])),
+                ("/gone", page(200, "gone", &[])),
+            ],
+        );
+        let new_run = run(
+            2,
+            vec![
+                ("/", page(200, "home", &["/a", "/b", "/new"])),
+                ("/a", page(200, "a2", &[])),
+                ("/b", page(404, "b", &[])),
+                ("/new", page(500, "new", &[])),
+            ],
+        );
+
+        let report = diff_report(&url("/"), &old_run, &new_run);
+        let lines: Vec<&str> = report.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "Added (1):",
+                "  https://example.com/new",
+                "Removed (1):",
+                "  https://example.com/gone",
+                "Changed (3):",
+                "  https://example.com/ (+1 -0 links)",
+                "    + https://example.com/new",
+                "  https://example.com/a (content changed)",
+                "  https://example.com/b (200 OK -> 404 Not Found)",
+                "Newly broken (2):",
+                "  https://example.com/b (404 Not Found)",
+                "  https://example.com/new (500 Internal Server Error)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_broken_links() {
+        let mut new_page = page(500, "new", &[]);
+        new_page.status = PageStatus::TimedOut;
+        let mut run = run(
+            1,
+            vec![
+                ("/", page(200, "home", &["/a", "/gone", "/new"])),
+                ("/a", page(200, "a", &["/gone"])),
+                ("/gone", page(404, "gone", &[])),
+                ("/new", new_page),
+            ],
+        );
+        let external_url = Url::parse("https://example.org/search/?q=1").unwrap();
+        run.pages.insert(external_url, page(404, "", &[]));
+        let url_links = HashMap::from([
+            (
+                url("/"),
+                vec![
+                    (url("/gone/#top"), edge("Gone", Some("top"))),
+                    (url("/gone"), edge("Gone", None)),
+                    (url("/new"), edge("New", None)),
+                    (
+                        Url::parse("https://example.org/search/?q=1#results").unwrap(),
+                        edge("Search", Some("results")),
+                    ),
+                ],
+            ),
+            (
+                url("/a"),
+                vec![(url("/gone?page=2"), edge("Old page", None))],
+            ),
+            // pages outside the run don't count
+            (url("/elsewhere"), vec![(url("/gone"), edge("Gone", None))]),
+        ]);
+
+        let broken = broken_links(&run, &url_links);
+        let urls: Vec<(&str, &str)> = broken
+            .iter()
+            .map(|broken_link| (broken_link.url.as_str(), broken_link.status.as_str()))
+            .collect();
+        assert_eq!(
+            urls,
+            vec![
+                ("https://example.com/gone", "404 Not Found"),
+                ("https://example.com/new", "timed out"),
+                ("https://example.org/search/?q=1", "404 Not Found"),
+            ]
+        );
+        let referrers = |broken_link: &BrokenLink| -> Vec<(String, Option<String>)> {
+            let referrers = broken_link.referrers.iter();
+            referrers
+                .map(|referrer| (referrer.page_url.clone(), referrer.text.clone()))
+                .collect()
+        };
+        // the same link from the same page is only listed once, whatever its query or fragment
+        assert_eq!(
+            referrers(&broken[0]),
+            vec![
+                ("https://example.com/".to_string(), Some("Gone".to_string())),
+                (
+                    "https://example.com/a".to_string(),
+                    Some("Old page".to_string())
+                ),
+            ]
+        );
+        assert_eq!(
+            referrers(&broken[1]),
+            vec![("https://example.com/".to_string(), Some("New".to_string()))]
+        );
+        assert_eq!(
+            referrers(&broken[2]),
+            vec![(
+                "https://example.com/".to_string(),
+                Some("Search".to_string())
+            )]
+        );
+    }
+
+    #[test]
+    fn test_missing_fragments() {
+        let mut docs = page(200, "docs", &[]);
+        docs.anchors = HashSet::from(["intro".to_string(), "café".to_string()]);
+        let unparsed = RunPage::unparsed(PageStatus::Http(200));
+        let run = run(
+            1,
+            vec![
+                ("/", page(200, "home", &["/docs", "/file.pdf"])),
+                ("/docs", docs),
+                ("/file.pdf", unparsed),
+            ],
+        );
+        let links = [
+            ("/docs#intro", Some("intro")),
+            ("/docs#caf%C3%A9", Some("café")),
+            ("/docs#setup", Some("setup")),
+            ("/docs#", Some("")),
+            ("/docs#top", Some("top")),
+            ("/docs#:~:text=intro", Some(":~:text=intro")),
+            // nothing is known about the elements of pages that weren't parsed
+            ("/file.pdf#page=2", Some("page=2")),
+        ];
+        let url_links = HashMap::from([(
+            url("/"),
+            links
+                .iter()
+                .map(|(path, fragment)| (url(path), edge("Docs", *fragment)))
+                .collect(),
+        )]);
+
+        let broken = broken_links(&run, &url_links);
+        assert_eq!(broken.len(), 1);
+        assert_eq!(broken[0].url, "https://example.com/docs#setup");
+        assert_eq!(broken[0].status, "missing anchor");
+        assert_eq!(broken[0].referrers[0].page_url, "https://example.com/");
+    }
+
+    #[test]
+    fn test_no_differences() {
+        let pages = || vec![("/", page(200, "home", &["/a"]))];
+        let report = diff_report(&url("/"), &run(1, pages()), &run(2, pages()));
+        assert!(report.starts_with("Comparing run 1 ("));
+        assert!(report.ends_with("No differences\n"));
+    }
+}
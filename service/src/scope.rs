@@ -0,0 +1,194 @@
+use url::Url;
+
+use shared::{HostScope, ScopePolicy};
+
+pub(crate) fn is_in_scope(policy: &ScopePolicy, base_url: &Url, url: &Url) -> bool {
+    if policy.any_scheme {
+        if !is_web_scheme(url.scheme()) {
+            return false;
+        }
+    } else if base_url.scheme() != url.scheme() {
+        return false;
+    }
+
+    let (Some(base_host), Some(host)) = (base_url.host_str(), url.host_str()) else {
+        return false;
+    };
+    if base_host == host {
+        return true;
+    }
+
+    match &policy.hosts {
+        HostScope::ExactHost => false,
+        HostScope::Subdomains => is_subdomain_of(host, base_host),
+        HostScope::RegistrableDomain => match (psl::domain_str(base_host), psl::domain_str(host)) {
+            (Some(base_domain), Some(domain)) => base_domain == domain,
+            _ => false,
+        },
+        HostScope::AllowedHosts(allowed_hosts) => allowed_hosts
+            .iter()
+            .any(|allowed_host| allowed_host.eq_ignore_ascii_case(host)),
+    }
+}
+
+// An in-scope URL as it's crawled. Crawls of any scheme fetch each page with the start URL's
+// scheme, so `http://` and `https://` links don't become two pages.
+pub(crate) fn with_crawl_scheme(policy: &ScopePolicy, base_url: &Url, mut url: Url) -> Url {
+    if policy.any_scheme && url.scheme() != base_url.scheme() {
+        // both are web schemes, which can always be swapped
+        let _ = url.set_scheme(base_url.scheme());
+    }
+    url
+}
+
+// The start URL's directory, which a path-scoped crawl is confined to
+pub(crate) fn path_scope_prefix(url: &Url) -> String {
+    let path = url.path();
+    if path.ends_with('/') {
+        return path.to_string();
+    }
+    let last_segment = path.rsplit('/').next().unwrap_or("");
+    if last_segment.contains('.') {
+        // looks like a file, so use its directory
+        path[..path.len() - last_segment.len()].to_string()
+    } else {
+        format!("{}/", path)
+    }
+}
+
+pub(crate) fn is_in_path_scope(base_url: &Url, url: &Url) -> bool {
+    let prefix = base_url.path();
+    let path = url.path();
+    // crawled urls have their trailing slash removed, so `/docs` is inside `/docs/`
+    path.starts_with(prefix) || prefix.strip_suffix('/') == Some(path)
+}
+
+fn is_web_scheme(scheme: &str) -> bool {
+    scheme == "http" || scheme == "https"
+}
+
+fn is_subdomain_of(host: &str, parent_host: &str) -> bool {
+    host.len() > parent_host.len()
+        && host.ends_with(parent_host)
+        && host.as_bytes()[host.len() - parent_host.len() - 1] == b'.'
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(hosts: HostScope, any_scheme: bool) -> ScopePolicy {
+        ScopePolicy { hosts, any_scheme }
+    }
+
+    fn in_scope(policy: &ScopePolicy, base_url: &str, url: &str) -> bool {
+        is_in_scope(
+            policy,
+            &Url::parse(base_url).unwrap(),
+            &Url::parse(url).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_exact_host() {
+        let policy = policy(HostScope::ExactHost, false);
+        let base_url = "https://example.com";
+        assert!(in_scope(&policy, base_url, "https://example.com/a"));
+        assert!(!in_scope(&policy, base_url, "http://example.com/a"));
+        assert!(!in_scope(&policy, base_url, "https://www.example.com/a"));
+        assert!(!in_scope(&policy, base_url, "https://other.com/a"));
+    }
+
+    #[test]
+    fn test_subdomains() {
+        let policy = policy(HostScope::Subdomains, false);
+        let base_url = "https://example.com";
+        assert!(in_scope(&policy, base_url, "https://example.com/a"));
+        assert!(in_scope(&policy, base_url, "https://docs.example.com/a"));
+        assert!(in_scope(&policy, base_url, "https://a.b.example.com/a"));
+        assert!(!in_scope(&policy, base_url, "https://badexample.com/a"));
+        assert!(!in_scope(
+            &policy,
+            "https://www.example.com",
+            "https://example.com/a"
+        ));
+    }
+
+    #[test]
+    fn test_registrable_domain() {
+        let policy = policy(HostScope::RegistrableDomain, false);
+        let base_url = "https://www.example.co.uk";
+        assert!(in_scope(&policy, base_url, "https://example.co.uk/a"));
+        assert!(in_scope(&policy, base_url, "https://docs.example.co.uk/a"));
+        assert!(!in_scope(&policy, base_url, "https://other.co.uk/a"));
+    }
+
+    #[test]
+    fn test_any_scheme() {
+        let policy = policy(HostScope::ExactHost, true);
+        let base_url = "https://example.com";
+        assert!(in_scope(&policy, base_url, "http://example.com/a"));
+        assert!(!in_scope(&policy, base_url, "ftp://example.com/a"));
+    }
+
+    #[test]
+    fn test_crawl_scheme() {
+        let base_url = Url::parse("https://example.com").unwrap();
+        let crawled = |any_scheme: bool, url: &str| {
+            let policy = policy(HostScope::ExactHost, any_scheme);
+            with_crawl_scheme(&policy, &base_url, Url::parse(url).unwrap()).to_string()
+        };
+        assert_eq!(
+            crawled(true, "http://example.com/a"),
+            "https://example.com/a"
+        );
+        assert_eq!(
+            crawled(true, "http://example.com:8080/a"),
+            "https://example.com:8080/a"
+        );
+        assert_eq!(
+            crawled(false, "http://example.com/a"),
+            "http://example.com/a"
+        );
+    }
+
+    #[test]
+    fn test_path_scope_prefix() {
+        let prefix = |url: &str| path_scope_prefix(&Url::parse(url).unwrap());
+        assert_eq!(prefix("https://example.com"), "/");
+        assert_eq!(prefix("https://example.com/docs/"), "/docs/");
+        assert_eq!(prefix("https://example.com/docs"), "/docs/");
+        assert_eq!(prefix("https://example.com/docs/index.html"), "/docs/");
+    }
+
+    #[test]
+    fn test_path_scope() {
+        let base_url = Url::parse("https://example.com/docs/").unwrap();
+        let in_path = |url: &str| is_in_path_scope(&base_url, &Url::parse(url).unwrap());
+        assert!(in_path("https://example.com/docs"));
+        assert!(in_path("https://example.com/docs/"));
+        assert!(in_path("https://example.com/docs/guide/intro"));
+        assert!(!in_path("https://example.com/docsets"));
+        assert!(!in_path("https://example.com/blog/post"));
+
+        let base_url = Url::parse("https://example.com/").unwrap();
+        assert!(is_in_path_scope(
+            &base_url,
+            &Url::parse("https://example.com/blog/post").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_allowed_hosts() {
+        let policy = policy(
+            HostScope::AllowedHosts(vec!["cdn.example.net".to_string()]),
+            false,
+        );
+        let base_url = "https://example.com";
+        assert!(in_scope(&policy, base_url, "https://example.com/a"));
+        assert!(in_scope(&policy, base_url, "https://CDN.example.net/a"));
+        assert!(!in_scope(&policy, base_url, "https://docs.example.com/a"));
+    }
+}
@@ -0,0 +1,91 @@
+use url::Url;
+
+use crate::error::CrawlerError;
+
+// Resolves user input such as `www.example.com` or `example.com/docs` into the URL a crawl
+// starts from. Inputs without a scheme are tried with `https` first, then `http`.
+pub(crate) async fn resolve_seed_url(input: &str) -> Result<Url, CrawlerError> {
+    let mut last_error = String::new();
+    for candidate in seed_url_candidates(input)? {
+        match reqwest::get(candidate.clone()).await {
+            // follow redirects, so the crawl is rooted where the site actually lives
+            Ok(response) => return Ok(response.url().clone()),
+            Err(err) => last_error = err.to_string(),
+        }
+    }
+    Err(CrawlerError::UnreachableSeedUrl(
+        input.to_string(),
+        last_error,
+    ))
+}
+
+pub(crate) fn seed_url_candidates(input: &str) -> Result<Vec<Url>, CrawlerError> {
+    let input = input.trim();
+    if input.contains("://") {
+        let url = Url::parse(input).map_err(CrawlerError::CannotParseLinkUrl)?;
+        return Ok(vec![url]);
+    }
+
+    let mut candidates = Vec::new();
+    for scheme in ["https", "http"] {
+        let url = Url::parse(&format!("{}://{}", scheme, input))
+            .map_err(CrawlerError::CannotParseLinkUrl)?;
+        candidates.push(url);
+    }
+    Ok(candidates)
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(input: &str) -> Vec<String> {
+        seed_url_candidates(input)
+            .unwrap()
+            .iter()
+            .map(|url| url.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_bare_host() {
+        assert_eq!(
+            candidates("www.example.com"),
+            vec!["https://www.example.com/", "http://www.example.com/"]
+        );
+    }
+
+    #[test]
+    fn test_host_and_path() {
+        assert_eq!(
+            candidates(" example.com/docs/intro "),
+            vec![
+                "https://example.com/docs/intro",
+                "http://example.com/docs/intro"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_host_and_port() {
+        assert_eq!(
+            candidates("localhost:8000"),
+            vec!["https://localhost:8000/", "http://localhost:8000/"]
+        );
+    }
+
+    #[test]
+    fn test_explicit_scheme() {
+        assert_eq!(
+            candidates("http://example.com"),
+            vec!["http://example.com/"]
+        );
+    }
+
+    #[test]
+    fn test_invalid_input() {
+        assert!(seed_url_candidates("exa mple.com").is_err());
+    }
+}
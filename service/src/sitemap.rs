@@ -0,0 +1,190 @@
+use std::collections::{HashSet, VecDeque};
+
+use anyhow::Result;
+use log::warn;
+use reqwest::{header::HeaderMap, Client, Method};
+use url::Url;
+
+use crate::fetcher::send_following_redirects;
+
+// Sitemaps beyond this many, counting the ones listed by sitemap indexes, aren't read
+const MAX_SITEMAPS: usize = 50;
+// The sitemaps protocol's limits on a single sitemap, which are also applied to all of a
+// site's sitemaps together
+const MAX_SITEMAP_URLS: usize = 50_000;
+const MAX_SITEMAP_BYTES: usize = 50 * 1024 * 1024;
+
+// What a sitemap lists: either pages, or more sitemaps for a sitemap index
+#[derive(Debug, PartialEq)]
+struct Sitemap {
+    is_index: bool,
+    locs: Vec<String>,
+}
+
+// The URLs listed by the site's sitemaps: the ones its `robots.txt` names, or `/sitemap.xml`
+// if it names none. Sitemaps that can't be read are skipped.
+pub(crate) async fn fetch_sitemap_urls(client: &Client, site_url: &Url) -> Vec<Url> {
+    let mut sitemap_urls = VecDeque::new();
+    if let Ok(robots_url) = site_url.join("/robots.txt") {
+        if let Ok(Some(robots)) = fetch_text(client, &robots_url).await {
+            sitemap_urls.extend(robots_sitemaps(&robots));
+        }
+    }
+    if sitemap_urls.is_empty() {
+        sitemap_urls.extend(site_url.join("/sitemap.xml").ok());
+    }
+
+    let mut read_sitemaps = HashSet::new();
+    let mut urls = Vec::new();
+    while let Some(sitemap_url) = sitemap_urls.pop_front() {
+        if read_sitemaps.len() == MAX_SITEMAPS || urls.len() >= MAX_SITEMAP_URLS {
+            break;
+        }
+        if !read_sitemaps.insert(sitemap_url.clone()) {
+            continue;
+        }
+        let text = match fetch_text(client, &sitemap_url).await {
+            Ok(Some(text)) => text,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("Failed to read sitemap {}: {}", sitemap_url, err);
+                continue;
+            }
+        };
+
+        let sitemap = parse_sitemap(&text);
+        let locs = sitemap.locs.iter().filter_map(|loc| Url::parse(loc).ok());
+        if sitemap.is_index {
+            sitemap_urls.extend(locs);
+        } else {
+            urls.extend(locs.take(MAX_SITEMAP_URLS - urls.len()));
+        }
+    }
+    urls
+}
+
+// The body of a successful response, or `None` for any other status
+async fn fetch_text(client: &Client, url: &Url) -> Result<Option<String>> {
+    let (mut response, _) =
+        send_following_redirects(client, Method::GET, url, &HeaderMap::new()).await?;
+    if !response.status().is_success() {
+        return Ok(None);
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        body.extend_from_slice(&chunk);
+        if body.len() >= MAX_SITEMAP_BYTES {
+            body.truncate(MAX_SITEMAP_BYTES);
+            break;
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+// The sitemaps named by `Sitemap:` lines of a `robots.txt`
+fn robots_sitemaps(robots: &str) -> Vec<Url> {
+    robots
+        .lines()
+        .filter_map(|line| {
+            let line = line.split('#').next().unwrap_or("");
+            let (name, value) = line.split_once(':')?;
+            if !name.trim().eq_ignore_ascii_case("sitemap") {
+                return None;
+            }
+            Url::parse(value.trim()).ok()
+        })
+        .collect()
+}
+
+// Reads the `<loc>`s of a sitemap or sitemap index. Sitemaps are simple enough to be scanned
+// for their tags rather than parsed as XML.
+fn parse_sitemap(xml: &str) -> Sitemap {
+    let mut locs = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find("<loc>") {
+        rest = &rest[start + "<loc>".len()..];
+        let Some(end) = rest.find("</loc>") else {
+            break;
+        };
+        let loc = rest[..end].trim();
+        let loc = loc
+            .strip_prefix("<![CDATA[")
+            .and_then(|loc| loc.strip_suffix("]]>"))
+            .map_or_else(|| unescape_xml(loc), |loc| loc.trim().to_string());
+        locs.push(loc);
+        rest = &rest[end..];
+    }
+    Sitemap {
+        is_index: xml.contains("<sitemapindex"),
+        locs,
+    }
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_robots_sitemaps() {
+        let robots = "User-agent: *\n\
+                      Disallow: /admin\n\
+                      SITEMAP: https://example.com/sitemap-pages.xml # the pages\n\
+                      Sitemap:https://example.com/sitemap-posts.xml\n\
+                      Sitemap: /relative.xml\n";
+        let sitemaps: Vec<String> = robots_sitemaps(robots)
+            .iter()
+            .map(|url| url.to_string())
+            .collect();
+        assert_eq!(
+            sitemaps,
+            vec![
+                "https://example.com/sitemap-pages.xml",
+                "https://example.com/sitemap-posts.xml"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_sitemap() {
+        let sitemap = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+              <url><loc>https://example.com/</loc><priority>1.0</priority></url>
+              <url><loc>
+                https://example.com/search?q=a&amp;page=2
+              </loc></url>
+              <url><loc><![CDATA[https://example.com/a&b]]></loc></url>
+            </urlset>"#;
+        assert_eq!(
+            parse_sitemap(sitemap),
+            Sitemap {
+                is_index: false,
+                locs: vec![
+                    "https://example.com/".to_string(),
+                    "https://example.com/search?q=a&page=2".to_string(),
+                    "https://example.com/a&b".to_string(),
+                ],
+            }
+        );
+
+        let index = r#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+              <sitemap><loc>https://example.com/sitemap-1.xml</loc></sitemap>
+            </sitemapindex>"#;
+        assert_eq!(
+            parse_sitemap(index),
+            Sitemap {
+                is_index: true,
+                locs: vec!["https://example.com/sitemap-1.xml".to_string()],
+            }
+        );
+    }
+}
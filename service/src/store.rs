@@ -0,0 +1,908 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use shared::{CrawlOptions, LinkEdge};
+
+use crate::{
+    base_url::CrawlStats,
+    error::CrawlerError,
+    node::Node,
+    recrawl::FetchHistory,
+    run::{start_run, CrawlRun, RunPage},
+};
+
+// How often the state is checkpointed while it's changing, on top of each crawl milestone
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+// Written at the start of each checkpoint. Bump it whenever a saved type changes, so that state
+// from another version is refused rather than misread.
+const STATE_FORMAT_VERSION: u32 = 5;
+
+// A link found on a page: the URL it resolved to, and the element it came from
+pub(crate) type PageLink = (Url, LinkEdge);
+
+// A change to the crawl state. Every change is appended to the journal as it happens, and
+// replaying the journal on startup rebuilds the state.
+#[derive(Serialize, Deserialize)]
+pub(crate) enum StateEvent {
+    SiteStarted {
+        base_url: Url,
+        start_url: Url,
+        options: CrawlOptions,
+        started_at: DateTime<Local>,
+    },
+    SiteStopped {
+        base_url: Url,
+    },
+    UrlExcluded {
+        base_url: Url,
+        url: Url,
+        reason: String,
+    },
+    NodeAdded {
+        url: Url,
+    },
+    ChildAdded {
+        parent_url: Url,
+        url: Url,
+    },
+    NodeUpdated {
+        url: Url,
+        node: Node,
+    },
+    WorkerCreated {
+        base_url: Url,
+        url: Url,
+    },
+    PageFetched {
+        base_url: Url,
+        url: Url,
+        history: FetchHistory,
+        not_modified: bool,
+    },
+    FetchFailed {
+        url: Url,
+        history: FetchHistory,
+    },
+    RunPageRecorded {
+        base_url: Url,
+        url: Url,
+        page: RunPage,
+    },
+    SitemapRecorded {
+        base_url: Url,
+        urls: Vec<Url>,
+    },
+    LinksCleared {
+        page_url: Url,
+    },
+    LinkRecorded {
+        page_url: Url,
+        url: Url,
+        edge: LinkEdge,
+    },
+    // A URL is waiting to be crawled, until the `Done` with the same job id. The same URL can
+    // be queued by several jobs at once.
+    Enqueued {
+        id: u64,
+        base_url: Url,
+        parent_url: Option<Url>,
+        url: Url,
+    },
+    Done {
+        id: u64,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct SavedSite {
+    pub(crate) crawling: bool,
+    pub(crate) options: CrawlOptions,
+    pub(crate) started_at: DateTime<Local>,
+    pub(crate) excluded_urls: HashMap<Url, String>,
+    pub(crate) stats: CrawlStats,
+    pub(crate) runs: Vec<CrawlRun>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct SavedWorker {
+    pub(crate) base_url: Url,
+    pub(crate) history: FetchHistory,
+}
+
+// A URL that was waiting to be crawled when the daemon stopped
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct SavedJob {
+    pub(crate) base_url: Url,
+    pub(crate) parent_url: Option<Url>,
+    pub(crate) url: Url,
+}
+
+// The crawl state rebuilt from the last checkpoint and the journals written since
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub(crate) struct SavedState {
+    pub(crate) sites: HashMap<Url, SavedSite>,
+    pub(crate) url_parents: HashMap<Url, HashSet<Url>>,
+    pub(crate) url_nodes: HashMap<Url, Node>,
+    pub(crate) url_links: HashMap<Url, Vec<PageLink>>,
+    pub(crate) workers: HashMap<Url, SavedWorker>,
+    // by job id
+    pub(crate) frontier: HashMap<u64, SavedJob>,
+}
+
+impl SavedState {
+    fn apply(&mut self, event: StateEvent) {
+        match event {
+            StateEvent::SiteStarted {
+                base_url,
+                start_url,
+                options,
+                started_at,
+            } => {
+                let site = self.sites.entry(base_url).or_insert_with(|| SavedSite {
+                    crawling: true,
+                    options: CrawlOptions::default(),
+                    started_at,
+                    excluded_urls: HashMap::new(),
+                    stats: CrawlStats::default(),
+                    runs: Vec::new(),
+                });
+                start_run(&mut site.runs, started_at, start_url);
+                site.crawling = true;
+                site.options = options;
+                site.started_at = started_at;
+                site.stats = CrawlStats::default();
+            }
+            StateEvent::SiteStopped { base_url } => {
+                if let Some(site) = self.sites.get_mut(&base_url) {
+                    site.crawling = false;
+                }
+            }
+            StateEvent::UrlExcluded {
+                base_url,
+                url,
+                reason,
+            } => {
+                if let Some(site) = self.sites.get_mut(&base_url) {
+                    site.excluded_urls.insert(url, reason);
+                }
+            }
+            StateEvent::NodeAdded { url } => {
+                self.url_parents.entry(url).or_default();
+            }
+            StateEvent::ChildAdded { parent_url, url } => {
+                self.url_parents.entry(parent_url).or_default().insert(url);
+            }
+            StateEvent::NodeUpdated { url, node } => {
+                self.url_nodes.insert(url, node);
+            }
+            StateEvent::WorkerCreated { base_url, url } => {
+                self.workers.entry(url).or_insert(SavedWorker {
+                    base_url,
+                    history: FetchHistory::default(),
+                });
+            }
+            StateEvent::PageFetched {
+                base_url,
+                url,
+                history,
+                not_modified,
+            } => {
+                if let Some(worker) = self.workers.get_mut(&url) {
+                    worker.history = history;
+                    worker.base_url = base_url.clone();
+                }
+                if let Some(site) = self.sites.get_mut(&base_url) {
+                    site.stats.count_fetch(not_modified);
+                }
+            }
+            StateEvent::FetchFailed { url, history } => {
+                if let Some(worker) = self.workers.get_mut(&url) {
+                    worker.history = history;
+                }
+            }
+            StateEvent::RunPageRecorded {
+                base_url,
+                url,
+                page,
+            } => {
+                let run = self
+                    .sites
+                    .get_mut(&base_url)
+                    .and_then(|site| site.runs.last_mut());
+                if let Some(run) = run {
+                    run.pages.insert(url, page);
+                }
+            }
+            StateEvent::SitemapRecorded { base_url, urls } => {
+                let run = self
+                    .sites
+                    .get_mut(&base_url)
+                    .and_then(|site| site.runs.last_mut());
+                if let Some(run) = run {
+                    run.sitemap_urls = urls;
+                }
+            }
+            StateEvent::LinksCleared { page_url } => {
+                self.url_links.remove(&page_url);
+            }
+            StateEvent::LinkRecorded {
+                page_url,
+                url,
+                edge,
+            } => {
+                self.url_links
+                    .entry(page_url)
+                    .or_default()
+                    .push((url, edge));
+            }
+            StateEvent::Enqueued {
+                id,
+                base_url,
+                parent_url,
+                url,
+            } => {
+                self.frontier.insert(
+                    id,
+                    SavedJob {
+                        base_url,
+                        parent_url,
+                        url,
+                    },
+                );
+            }
+            StateEvent::Done { id } => {
+                self.frontier.remove(&id);
+            }
+        }
+    }
+}
+
+// Appends state changes to the journal in the state directory, from a thread of its own so
+// recording never blocks the crawl. That thread also checkpoints the whole state now and then,
+// starting a new journal each time, so a restart only replays what changed since.
+#[derive(Clone)]
+pub(crate) struct Store {
+    sender: mpsc::Sender<StateEvent>,
+}
+
+impl Store {
+    // Opens the state directory, creating it if needed, and loads what it holds. Whatever a
+    // crash left half written is dropped.
+    pub(crate) fn open(state_dir: &Path) -> Result<(Self, SavedState)> {
+        fs::create_dir_all(state_dir)?;
+        remove_unfinished_checkpoints(state_dir)?;
+
+        let (mut saved_state, checkpoint_generation) = load_checkpoint(state_dir)?;
+        // the journals older than the checkpoint are already part of it
+        let journal_generations: Vec<u64> = list_generations(state_dir, "journal")?
+            .into_iter()
+            .filter(|&generation| generation >= checkpoint_generation)
+            .collect();
+        let mut event_count = 0;
+        for &generation in &journal_generations {
+            event_count += replay_journal(&journal_path(state_dir, generation), &mut saved_state)?;
+        }
+        info!(
+            "Loaded checkpoint {} and {} events since from {}",
+            checkpoint_generation,
+            event_count,
+            state_dir.display()
+        );
+
+        let generation = journal_generations
+            .last()
+            .copied()
+            .unwrap_or(checkpoint_generation);
+        let mut writer = StateWriter {
+            state_dir: state_dir.to_path_buf(),
+            state: saved_state.clone(),
+            generation,
+            journal: BufWriter::new(open_journal(state_dir, generation)?),
+            events_since_checkpoint: event_count,
+            last_checkpoint: Instant::now(),
+        };
+        // so the next restart doesn't replay the same events again
+        if event_count > 0 {
+            writer.checkpoint();
+        }
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("state-writer".to_string())
+            .spawn(move || writer.run(receiver))?;
+
+        Ok((Self { sender }, saved_state))
+    }
+
+    pub(crate) fn record(&self, event: StateEvent) {
+        // the writer thread only stops if writing failed, which it has already reported
+        let _ = self.sender.send(event);
+    }
+}
+
+// Owns the files in the state directory, and keeps its own copy of the state to checkpoint
+struct StateWriter {
+    state_dir: PathBuf,
+    state: SavedState,
+    // of the journal being appended to, and of the checkpoint it follows
+    generation: u64,
+    journal: BufWriter<File>,
+    events_since_checkpoint: usize,
+    last_checkpoint: Instant,
+}
+
+impl StateWriter {
+    fn run(mut self, receiver: mpsc::Receiver<StateEvent>) {
+        loop {
+            let timeout = CHECKPOINT_INTERVAL.saturating_sub(self.last_checkpoint.elapsed());
+            match receiver.recv_timeout(timeout) {
+                Ok(event) => {
+                    // write whatever else is waiting before flushing
+                    let events = std::iter::once(event).chain(receiver.try_iter());
+                    match self.write_events(events) {
+                        Ok(milestone) => {
+                            if milestone {
+                                self.checkpoint();
+                            }
+                        }
+                        Err(err) => {
+                            error!(
+                                "Failed to write the crawl state, it won't be saved: {:#}",
+                                err
+                            );
+                            return;
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.events_since_checkpoint > 0 {
+                        self.checkpoint();
+                    } else {
+                        self.last_checkpoint = Instant::now();
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    if self.events_since_checkpoint > 0 {
+                        self.checkpoint();
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    // Journals the events, returning whether any of them was a crawl milestone
+    fn write_events(&mut self, events: impl Iterator<Item = StateEvent>) -> Result<bool> {
+        let mut milestone = false;
+        for event in events {
+            write_record(&mut self.journal, &event)?;
+            milestone |= self.apply(event);
+        }
+        self.journal.flush()?;
+        Ok(milestone)
+    }
+
+    // Applies an event to the copy of the state, returning whether it's a crawl milestone: a
+    // site starting or stopping, or running out of URLs to crawl
+    fn apply(&mut self, event: StateEvent) -> bool {
+        self.events_since_checkpoint += 1;
+        let milestone = match &event {
+            StateEvent::SiteStarted { .. } | StateEvent::SiteStopped { .. } => true,
+            StateEvent::Done { id } => self.state.frontier.get(id).is_some_and(|done_job| {
+                self.state
+                    .frontier
+                    .iter()
+                    .all(|(job_id, job)| job_id == id || job.base_url != done_job.base_url)
+            }),
+            _ => false,
+        };
+        self.state.apply(event);
+        milestone
+    }
+
+    fn checkpoint(&mut self) {
+        self.events_since_checkpoint = 0;
+        self.last_checkpoint = Instant::now();
+        match self.write_checkpoint() {
+            Ok(()) => info!("Wrote checkpoint {}", self.generation),
+            Err(err) => error!("Failed to write a checkpoint: {:#}", err),
+        }
+    }
+
+    // Writes the state as the next generation's checkpoint. The next journal is started first,
+    // so that if writing the checkpoint fails, the previous one and the journals since it
+    // still hold everything.
+    fn write_checkpoint(&mut self) -> Result<()> {
+        self.journal.flush()?;
+        let generation = self.generation + 1;
+        self.journal = BufWriter::new(open_journal(&self.state_dir, generation)?);
+        self.generation = generation;
+        write_checkpoint(&self.state_dir, generation, &self.state)?;
+
+        // the previous checkpoint is kept, in case this one turns out to be damaged
+        for prefix in ["checkpoint", "journal"] {
+            for old_generation in list_generations(&self.state_dir, prefix)? {
+                if old_generation + 1 < generation {
+                    fs::remove_file(generation_path(&self.state_dir, prefix, old_generation))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn checkpoint_path(state_dir: &Path, generation: u64) -> PathBuf {
+    generation_path(state_dir, "checkpoint", generation)
+}
+
+fn journal_path(state_dir: &Path, generation: u64) -> PathBuf {
+    generation_path(state_dir, "journal", generation)
+}
+
+fn generation_path(state_dir: &Path, prefix: &str, generation: u64) -> PathBuf {
+    state_dir.join(format!("{}-{:08}.bin", prefix, generation))
+}
+
+// The generations of the `{prefix}-{generation}.bin` files in the state directory, oldest first
+fn list_generations(state_dir: &Path, prefix: &str) -> Result<Vec<u64>> {
+    let mut generations = Vec::new();
+    for entry in fs::read_dir(state_dir)? {
+        let file_name = entry?.file_name();
+        let generation = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix(prefix))
+            .and_then(|name| name.strip_prefix('-'))
+            .and_then(|name| name.strip_suffix(".bin"))
+            .and_then(|generation| generation.parse::<u64>().ok());
+        generations.extend(generation);
+    }
+    generations.sort_unstable();
+    Ok(generations)
+}
+
+fn open_journal(state_dir: &Path, generation: u64) -> Result<File> {
+    let journal = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(journal_path(state_dir, generation))?;
+    Ok(journal)
+}
+
+// A checkpoint that was still being written when the daemon stopped
+fn remove_unfinished_checkpoints(state_dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(state_dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            warn!("Removing an unfinished checkpoint: {}", path.display());
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+// The newest checkpoint that's intact, along with its generation. Damaged ones are removed, and
+// the one before is used instead. One that's intact but can't be read is an error, so that it's
+// never thrown away.
+fn load_checkpoint(state_dir: &Path) -> Result<(SavedState, u64)> {
+    for generation in list_generations(state_dir, "checkpoint")?.into_iter().rev() {
+        let path = checkpoint_path(state_dir, generation);
+        if let Some(saved_state) = read_checkpoint(&path)? {
+            return Ok((saved_state, generation));
+        }
+        warn!("Removing a damaged checkpoint: {}", path.display());
+        fs::remove_file(&path)?;
+    }
+    Ok((SavedState::default(), 0))
+}
+
+// Each checkpoint is the format version and the encoded state, followed by their checksum. It
+// only gets its name once it's completely written.
+fn write_checkpoint(state_dir: &Path, generation: u64, saved_state: &SavedState) -> Result<()> {
+    let mut bytes = STATE_FORMAT_VERSION.to_le_bytes().to_vec();
+    bincode::serialize_into(&mut bytes, saved_state)?;
+    let path = checkpoint_path(state_dir, generation);
+    let unfinished_path = path.with_extension("tmp");
+    let mut file = File::create(&unfinished_path)?;
+    file.write_all(&bytes)?;
+    file.write_all(&crc32fast::hash(&bytes).to_le_bytes())?;
+    file.sync_all()?;
+    fs::rename(&unfinished_path, &path)?;
+    File::open(state_dir)?.sync_all()?;
+    Ok(())
+}
+
+// The checkpoint's state, or `None` if it's damaged
+fn read_checkpoint(path: &Path) -> Result<Option<SavedState>> {
+    let bytes = fs::read(path)?;
+    let Some(checked_length) = bytes.len().checked_sub(4) else {
+        return Ok(None);
+    };
+    let (checked_bytes, checksum_bytes) = bytes.split_at(checked_length);
+    if crc32fast::hash(checked_bytes).to_le_bytes() != checksum_bytes {
+        return Ok(None);
+    }
+
+    // the checkpoint is as it was written, so only another version can make it unreadable
+    let incompatible =
+        |reason: String| CrawlerError::IncompatibleState(path.display().to_string(), reason);
+    let Some((version_bytes, state_bytes)) = checked_bytes.split_first_chunk::<4>() else {
+        return Err(incompatible("no format version".to_string()).into());
+    };
+    let version = u32::from_le_bytes(*version_bytes);
+    if version != STATE_FORMAT_VERSION {
+        return Err(incompatible(format!(
+            "format version {}, expected {}",
+            version, STATE_FORMAT_VERSION
+        ))
+        .into());
+    }
+    let saved_state =
+        bincode::deserialize(state_bytes).map_err(|err| incompatible(err.to_string()))?;
+    Ok(Some(saved_state))
+}
+
+// Applies the journal's events, returning how many there were. A partially written event at
+// the end is cut off.
+fn replay_journal(path: &Path, saved_state: &mut SavedState) -> Result<usize> {
+    let mut journal = OpenOptions::new().read(true).write(true).open(path)?;
+    let (event_count, valid_length) = replay(path, &mut journal, saved_state)?;
+    if valid_length < journal.metadata()?.len() {
+        // the daemon stopped halfway through writing an event
+        warn!(
+            "Dropping a partially written event at the end of {}",
+            path.display()
+        );
+        journal.set_len(valid_length)?;
+    }
+    Ok(event_count)
+}
+
+// Applies the journal's events, returning how many there were and the length of the
+// journal up to the last complete one
+fn replay(path: &Path, journal: &mut File, saved_state: &mut SavedState) -> Result<(usize, u64)> {
+    let mut bytes = Vec::new();
+    journal.read_to_end(&mut bytes)?;
+
+    let mut event_count = 0;
+    let mut position = 0;
+    while let Some((event_bytes, record_length)) = read_record(&bytes[position..]) {
+        // a complete record that can't be decoded isn't from a crash, so it's never cut off
+        let event = bincode::deserialize(event_bytes).map_err(|err| {
+            CrawlerError::IncompatibleState(
+                path.display().to_string(),
+                format!("event {}: {}", event_count + 1, err),
+            )
+        })?;
+        saved_state.apply(event);
+        event_count += 1;
+        position += record_length;
+    }
+    Ok((event_count, position as u64))
+}
+
+// Each record is the length of the encoded event, followed by the event. `None` if the record
+// runs past the end of the journal.
+fn read_record(bytes: &[u8]) -> Option<(&[u8], usize)> {
+    let (length_bytes, rest) = bytes.split_first_chunk::<4>()?;
+    let length = u32::from_le_bytes(*length_bytes) as usize;
+    let event_bytes = rest.get(..length)?;
+    Some((event_bytes, 4 + length))
+}
+
+fn write_record(writer: &mut impl Write, event: &StateEvent) -> Result<()> {
+    let event_bytes = bincode::serialize(event)?;
+    writer.write_all(&(event_bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(&event_bytes)?;
+    Ok(())
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::{
+        run::PageStatus,
+        test_util::{anchor_edge, url},
+    };
+
+    fn state_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("crawler-store-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn write_journal(dir: &Path, events: &[StateEvent]) {
+        fs::create_dir_all(dir).unwrap();
+        let mut bytes = Vec::new();
+        for event in events {
+            write_record(&mut bytes, event).unwrap();
+        }
+        fs::write(journal_path(dir, 0), bytes).unwrap();
+    }
+
+    fn fetched_now() -> FetchHistory {
+        let mut history = FetchHistory::default();
+        history.last_fetched_at = Some(Local::now());
+        history
+    }
+
+    #[test]
+    fn test_replay() {
+        let dir = state_dir("replay");
+        let base_url = url("https://example.com/");
+        let page_url = url("https://example.com/a");
+        write_journal(
+            &dir,
+            &[
+                StateEvent::SiteStarted {
+                    base_url: base_url.clone(),
+                    start_url: base_url.clone(),
+                    options: CrawlOptions::default(),
+                    started_at: Local::now(),
+                },
+                StateEvent::NodeAdded {
+                    url: base_url.clone(),
+                },
+                StateEvent::ChildAdded {
+                    parent_url: base_url.clone(),
+                    url: page_url.clone(),
+                },
+                StateEvent::WorkerCreated {
+                    base_url: base_url.clone(),
+                    url: page_url.clone(),
+                },
+                StateEvent::PageFetched {
+                    base_url: base_url.clone(),
+                    url: page_url.clone(),
+                    history: fetched_now(),
+                    not_modified: false,
+                },
+                StateEvent::RunPageRecorded {
+                    base_url: base_url.clone(),
+                    url: page_url.clone(),
+                    page: RunPage {
+                        status: PageStatus::Http(200),
+                        content_hash: Some("hash".to_string()),
+                        outlinks: vec![url("https://example.com/b")],
+                        anchors: HashSet::new(),
+                        truncated: false,
+                    },
+                },
+                StateEvent::LinkRecorded {
+                    page_url: page_url.clone(),
+                    url: url("https://example.com/b"),
+                    edge: anchor_edge("B"),
+                },
+                StateEvent::Enqueued {
+                    id: 0,
+                    base_url: base_url.clone(),
+                    parent_url: Some(page_url.clone()),
+                    url: url("https://example.com/b"),
+                },
+                StateEvent::Enqueued {
+                    id: 1,
+                    base_url: base_url.clone(),
+                    parent_url: None,
+                    url: page_url.clone(),
+                },
+                StateEvent::Done { id: 1 },
+            ],
+        );
+
+        let (_store, saved_state) = Store::open(&dir).unwrap();
+        assert!(saved_state.sites[&base_url].crawling);
+        assert!(saved_state.url_parents[&base_url].contains(&page_url));
+        assert!(saved_state.workers[&page_url]
+            .history
+            .last_fetched_at
+            .is_some());
+        assert_eq!(saved_state.url_links[&page_url].len(), 1);
+        assert_eq!(saved_state.sites[&base_url].stats.pages_fetched, 1);
+        let runs = &saved_state.sites[&base_url].runs;
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].pages[&page_url].outlinks.len(), 1);
+        let frontier: Vec<&Url> = saved_state.frontier.values().map(|job| &job.url).collect();
+        assert_eq!(frontier, vec![&url("https://example.com/b")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recorded_events_are_reloaded() {
+        let dir = state_dir("reload");
+        let base_url = url("https://example.com/");
+        {
+            let (store, saved_state) = Store::open(&dir).unwrap();
+            assert!(saved_state.sites.is_empty());
+            store.record(StateEvent::SiteStarted {
+                base_url: base_url.clone(),
+                start_url: base_url.clone(),
+                options: CrawlOptions::default(),
+                started_at: Local::now(),
+            });
+            store.record(StateEvent::SiteStopped {
+                base_url: base_url.clone(),
+            });
+        }
+
+        // the writer thread checkpoints each milestone in the background
+        for _ in 0..100 {
+            let generations = list_generations(&dir, "checkpoint").unwrap();
+            if let Some(&generation) = generations.last() {
+                let saved_state = read_checkpoint(&checkpoint_path(&dir, generation))
+                    .unwrap()
+                    .unwrap();
+                if saved_state
+                    .sites
+                    .get(&base_url)
+                    .is_some_and(|site| !site.crawling)
+                {
+                    fs::remove_dir_all(&dir).unwrap();
+                    return;
+                }
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        panic!("the recorded events were never checkpointed");
+    }
+
+    #[test]
+    fn test_partially_written_event_is_dropped() {
+        let dir = state_dir("torn");
+        let base_url = url("https://example.com/");
+        write_journal(
+            &dir,
+            &[StateEvent::SiteStarted {
+                base_url: base_url.clone(),
+                start_url: base_url.clone(),
+                options: CrawlOptions::default(),
+                started_at: Local::now(),
+            }],
+        );
+        let journal_path = journal_path(&dir, 0);
+        let complete_length = fs::metadata(&journal_path).unwrap().len();
+        let mut journal = OpenOptions::new().append(true).open(&journal_path).unwrap();
+        journal.write_all(&[200, 0, 0, 0, 1, 2, 3]).unwrap();
+        drop(journal);
+
+        let (_store, saved_state) = Store::open(&dir).unwrap();
+        assert!(saved_state.sites.contains_key(&base_url));
+        let repaired_length = fs::metadata(&journal_path).unwrap().len();
+        assert_eq!(repaired_length, complete_length);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_undecodable_event_is_kept() {
+        let dir = state_dir("undecodable");
+        write_journal(&dir, &[]);
+        let journal_path = journal_path(&dir, 0);
+        fs::write(&journal_path, [3, 0, 0, 0, 255, 255, 255]).unwrap();
+
+        assert!(Store::open(&dir).is_err());
+        assert_eq!(fs::metadata(&journal_path).unwrap().len(), 7);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn site_started(base_url: &Url) -> StateEvent {
+        StateEvent::SiteStarted {
+            base_url: base_url.clone(),
+            start_url: base_url.clone(),
+            options: CrawlOptions::default(),
+            started_at: Local::now(),
+        }
+    }
+
+    #[test]
+    fn test_damaged_checkpoint_falls_back_to_previous() {
+        let dir = state_dir("damaged");
+        let first_url = url("https://example.com/");
+        let second_url = url("https://example.org/");
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut saved_state = SavedState::default();
+        saved_state.apply(site_started(&first_url));
+        write_checkpoint(&dir, 1, &saved_state).unwrap();
+        let mut journal = Vec::new();
+        write_record(&mut journal, &site_started(&second_url)).unwrap();
+        fs::write(journal_path(&dir, 1), journal).unwrap();
+
+        // the newest checkpoint got cut short, and another was never finished
+        saved_state.apply(site_started(&second_url));
+        write_checkpoint(&dir, 2, &saved_state).unwrap();
+        let damaged_path = checkpoint_path(&dir, 2);
+        let damaged_length = fs::metadata(&damaged_path).unwrap().len();
+        let damaged = OpenOptions::new().write(true).open(&damaged_path).unwrap();
+        damaged.set_len(damaged_length / 2).unwrap();
+        fs::write(checkpoint_path(&dir, 7).with_extension("tmp"), b"partial").unwrap();
+        let mut journal = Vec::new();
+        let stopped = StateEvent::SiteStopped {
+            base_url: first_url.clone(),
+        };
+        write_record(&mut journal, &stopped).unwrap();
+        fs::write(journal_path(&dir, 2), journal).unwrap();
+
+        let (_store, saved_state) = Store::open(&dir).unwrap();
+        assert!(!saved_state.sites[&first_url].crawling);
+        assert!(saved_state.sites[&second_url].crawling);
+        assert!(!checkpoint_path(&dir, 7).with_extension("tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_incompatible_checkpoint_is_kept() {
+        let dir = state_dir("incompatible");
+        fs::create_dir_all(&dir).unwrap();
+        let write_unchecked = |bytes: &[u8]| {
+            let mut file = File::create(checkpoint_path(&dir, 1)).unwrap();
+            file.write_all(bytes).unwrap();
+            file.write_all(&crc32fast::hash(bytes).to_le_bytes())
+                .unwrap();
+        };
+
+        // written by another version
+        let mut bytes = (STATE_FORMAT_VERSION + 1).to_le_bytes().to_vec();
+        bincode::serialize_into(&mut bytes, &SavedState::default()).unwrap();
+        write_unchecked(&bytes);
+        assert!(Store::open(&dir).is_err());
+        assert!(checkpoint_path(&dir, 1).exists());
+
+        // the right version, but not a state it can decode
+        let mut bytes = STATE_FORMAT_VERSION.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&[1, 2, 3]);
+        write_unchecked(&bytes);
+        assert!(Store::open(&dir).is_err());
+        assert!(checkpoint_path(&dir, 1).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_milestones() {
+        let dir = state_dir("milestones");
+        fs::create_dir_all(&dir).unwrap();
+        let mut writer = StateWriter {
+            state_dir: dir.clone(),
+            state: SavedState::default(),
+            generation: 0,
+            journal: BufWriter::new(open_journal(&dir, 0).unwrap()),
+            events_since_checkpoint: 0,
+            last_checkpoint: Instant::now(),
+        };
+        let base_url = url("https://example.com/");
+        let enqueued = |id: u64, path: &str| StateEvent::Enqueued {
+            id,
+            base_url: base_url.clone(),
+            parent_url: None,
+            url: base_url.join(path).unwrap(),
+        };
+        let done = |id: u64| StateEvent::Done { id };
+
+        assert!(writer.apply(site_started(&base_url)));
+        assert!(!writer.apply(enqueued(0, "a")));
+        assert!(!writer.apply(enqueued(1, "b")));
+        // another job still has `b` queued
+        assert!(!writer.apply(enqueued(2, "b")));
+        assert!(!writer.apply(done(0)));
+        assert!(!writer.apply(done(1)));
+        // the site has nothing left to crawl
+        assert!(writer.apply(done(2)));
+        assert!(!writer.apply(done(2)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
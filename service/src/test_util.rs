@@ -0,0 +1,25 @@
+use shared::{LinkEdge, LinkKind};
+use url::Url;
+
+// A URL of the site the tests crawl, from its path
+pub(crate) fn url(path: &str) -> Url {
+    Url::parse("https://example.com/")
+        .unwrap()
+        .join(path)
+        .unwrap()
+}
+
+// A plain `<a href>` link with the text, outside the page's navigation
+pub(crate) fn anchor_edge(text: &str) -> LinkEdge {
+    LinkEdge {
+        kind: LinkKind::AnchorHref,
+        text: Some(text.to_string()),
+        title: None,
+        rel: None,
+        hreflang: None,
+        target: None,
+        fragment: None,
+        in_nav: false,
+        line: 1,
+    }
+}
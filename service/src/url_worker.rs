@@ -1,89 +1,283 @@
+use std::borrow::Cow;
+
 use anyhow::Result;
-use chrono::{DateTime, Local};
+use chrono::Local;
+use encoding_rs::{Encoding, UTF_8};
 use log::info;
+use percent_encoding::percent_decode_str;
+use reqwest::header::HeaderMap;
+use tokio::sync::oneshot;
 use url::{ParseError, Url};
 
+use shared::CrawlOptions;
+
 use crate::{
     crawler::Crawler,
     error::{print_error_and_backtrace, CrawlerError},
-    parser::find_anchors,
+    fetcher::{fetch_page, FetchOutcome, FetchedPage, HtmlBody, Validators},
+    parse_pool::{ChunkConsumer, ParseStream},
+    parser::{Link, LinkParser, PageAnalysis},
+    recrawl::{FetchHistory, Recrawl},
+    robots::{is_rel_nofollow, RobotsDirectives},
+    run::{PageStatus, RunPage},
+    store::StateEvent,
 };
 
-const URL_MAX_STALE_MINUTES: i64 = 1;
-const PARSER_WORKER_COUNT: usize = 4;
-
 pub struct UrlWorker {
     crawler: Crawler,
+    base_url: Url,
     url: Url,
-    last_access_timestamp: Option<DateTime<Local>>,
+    history: FetchHistory,
 }
 
 impl UrlWorker {
-    pub fn new(crawler: Crawler, url: &Url) -> Result<Self> {
+    pub fn new(crawler: Crawler, base_url: &Url, url: &Url) -> Result<Self> {
+        let base_url = base_url.clone();
         let url = url.clone();
 
         Ok(Self {
             crawler,
+            base_url,
             url,
-            last_access_timestamp: None,
+            history: FetchHistory::default(),
         })
     }
 
-    pub async fn start(&mut self) -> Result<()> {
-        if let Some(timestamp) = self.last_access_timestamp {
-            let now = Local::now();
-            let duration = now.signed_duration_since(timestamp);
-            if duration.num_minutes() < URL_MAX_STALE_MINUTES {
-                // info!("Skip Crawling URL (already fetched within last 5 minutes): {}", self.url);
-                return Ok(());
-            }
+    pub(crate) fn restore(
+        crawler: Crawler,
+        base_url: Url,
+        url: Url,
+        history: FetchHistory,
+    ) -> Self {
+        Self {
+            crawler,
+            base_url,
+            url,
+            history,
+        }
+    }
+
+    pub(crate) fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    pub(crate) fn history(&self) -> &FetchHistory {
+        &self.history
+    }
+
+    pub async fn start(
+        &mut self,
+        base_url: &Url,
+        options: &CrawlOptions,
+        recrawl: &Recrawl,
+    ) -> Result<()> {
+        if !recrawl.is_due(&self.history, Local::now()) {
+            return Ok(());
         }
+        // the page belongs to the crawl fetching it now, which may not be the one that found it
+        self.base_url = base_url.clone();
 
-        // Fetch page content using reqwest
+        // Fetch page content using reqwest. Pages that haven't changed since they were last
+        // fetched aren't downloaded again.
         info!("Crawling URL: {}", self.url);
-        let document = reqwest::get(self.url.clone()).await?.text().await?;
+        let fetched = fetch_page(
+            self.crawler.http_client(),
+            &self.url,
+            &options.fetch,
+            self.history.validators(),
+        )
+        .await;
+        let FetchedPage {
+            status,
+            headers,
+            redirects,
+            outcome,
+        } = match fetched {
+            Ok(fetched) => fetched,
+            Err(err) => {
+                // so the page isn't fetched again until the policy says so
+                self.history.record_failure(Local::now());
+                self.crawler.store().record(StateEvent::FetchFailed {
+                    url: self.url.clone(),
+                    history: self.history.clone(),
+                });
+                let status = PageStatus::from_error(&err);
+                self.record_run_page(RunPage::unparsed(status)).await;
+                return Err(err);
+            }
+        };
         // info!("Received response from URL: {}", self.url);
 
-        // store timestamp
-        self.last_access_timestamp = Some(Local::now());
+        let fetched_at = Local::now();
+        let document_url = document_url(&self.url, &redirects);
+        let validators = Validators::from_headers(&headers);
+        let status = PageStatus::Http(status.as_u16());
 
-        // Spin up Parser Workers
-        for worker_index in 0..PARSER_WORKER_COUNT {
-            let crawler_clone = self.crawler.clone();
-            let url_clone = self.url.clone();
-            let document_clone = document.clone();
-            tokio::spawn(async move {
-                Self::parser_worker(crawler_clone, worker_index, url_clone, document_clone);
-            });
+        if !matches!(outcome, FetchOutcome::NotModified) {
+            let header_robots = self.header_robots(&headers, options);
+            self.crawler
+                .update_node(&self.url, |node| {
+                    node.noindex = header_robots.noindex;
+                    node.nofollow = header_robots.nofollow;
+                    node.metadata = None;
+                    node.truncated = false;
+                    node.redirects = redirects;
+                })
+                .await;
+            // the links found last time are replaced by the ones parsed from this response
+            self.crawler.clear_links(&self.url).await;
         }
 
-        Ok(())
+        let mut body = match outcome {
+            FetchOutcome::Html(body) => body,
+            FetchOutcome::NotModified => {
+                self.history
+                    .record_not_modified(fetched_at, validators, recrawl);
+                self.record_fetch(true).await;
+                // the page is still as an earlier run found it
+                let last_page = self.crawler.last_run_page(&self.base_url, &self.url).await;
+                if let Some(page) = last_page {
+                    self.record_run_page(page).await;
+                }
+                self.follow_recorded_links(options).await;
+                return Ok(());
+            }
+            FetchOutcome::NotHtml(mime_type) => {
+                // keep it as a leaf, without downloading the body
+                self.crawler
+                    .update_node(&self.url, |node| node.mime_type = Some(mime_type))
+                    .await;
+                self.history.record(fetched_at, None, validators, recrawl);
+                self.record_fetch(false).await;
+                self.record_run_page(RunPage::unparsed(status)).await;
+                return Ok(());
+            }
+        };
+        self.crawler
+            .update_node(&self.url, |node| {
+                node.mime_type = Some("text/html".to_string())
+            })
+            .await;
+
+        // Links are parsed on the parser threads while the page is still downloading, and
+        // each one is resolved and enqueued in a task of its own
+        let collector = LinkCollector {
+            crawler: self.crawler.clone(),
+            base_url: self.base_url.clone(),
+            page_url: self.url.clone(),
+            document_url,
+            options: options.clone(),
+            header_robots: self.header_robots(&headers, options),
+        };
+        let encoding = body.encoding();
+        let (summary_sender, summary_receiver) = oneshot::channel();
+        let stream = self
+            .crawler
+            .parse_pool()
+            .start(move || Box::new(collector.into_consumer(encoding, summary_sender)))
+            .await?;
+        let result = Self::stream_body(&mut body, &stream).await;
+        // whatever arrived before an error is still parsed, and every link found is enqueued
+        // before the page counts as done
+        stream.finish().await?;
+        let summary = summary_receiver.await.unwrap_or_default();
+        let analysis = summary.analysis;
+        let metadata = analysis.metadata;
+        let truncated = body.is_truncated();
+        self.crawler
+            .update_node(&self.url, |node| {
+                node.metadata = Some(metadata);
+                node.truncated = truncated;
+            })
+            .await;
+        // an incomplete or truncated body can't tell whether the page changed, and its links
+        // weren't all recorded, so it mustn't be answered with a 304 next time
+        let complete = result.is_ok() && !truncated;
+        let content_hash = complete.then_some(analysis.content_hash);
+        let validators = if complete {
+            validators
+        } else {
+            Validators::default()
+        };
+        self.history
+            .record(fetched_at, content_hash.clone(), validators, recrawl);
+        self.record_fetch(false).await;
+        self.record_run_page(RunPage {
+            status,
+            content_hash,
+            outlinks: summary.outlinks,
+            anchors: analysis.anchors,
+            truncated,
+        })
+        .await;
+        result
     }
 
-    fn parser_worker(crawler: Crawler, worker_index: usize, previous_url: Url, document: String) {
-        for link_url in find_anchors(document.as_str(), worker_index, PARSER_WORKER_COUNT) {
-            match Self::parser_worker_handle_link(&previous_url, link_url.as_str()) {
-                Ok(link_url) => {
-                    let previous_url_clone = previous_url.clone();
-                    let crawler = crawler.clone();
-                    tokio::spawn(async move {
-                        if let Err(err) = crawler
-                            .start_job(Some(&previous_url_clone), &link_url)
-                            .await
-                        {
-                            print_error_and_backtrace(err);
-                        }
-                    });
-                }
-                Err(err) => {
-                    err.print();
-                }
+    fn header_robots(&self, headers: &HeaderMap, options: &CrawlOptions) -> RobotsDirectives {
+        if options.robots.x_robots_tag {
+            let values = headers.get_all("x-robots-tag").iter();
+            RobotsDirectives::parse_header(values.filter_map(|value| value.to_str().ok()))
+        } else {
+            RobotsDirectives::default()
+        }
+    }
+
+    // Follows the links recorded from the page's last full response again, as parsing it
+    // would have
+    async fn follow_recorded_links(&self, options: &CrawlOptions) {
+        let page_nofollow = self
+            .crawler
+            .node(&self.url)
+            .await
+            .is_some_and(|node| node.nofollow);
+        if page_nofollow {
+            return;
+        }
+        for (link_url, edge) in self.crawler.page_links(&self.url).await {
+            let link_nofollow =
+                options.robots.rel_nofollow && edge.rel.as_deref().is_some_and(is_rel_nofollow);
+            if edge.kind.is_navigational() && !link_nofollow {
+                self.crawler
+                    .spawn_job(self.base_url.clone(), Some(self.url.clone()), link_url);
             }
         }
     }
 
-    fn parser_worker_handle_link(previous_url: &Url, link_url: &str) -> Result<Url, CrawlerError> {
-        let link_url = match Url::parse(link_url) {
+    async fn stream_body(body: &mut HtmlBody, stream: &ParseStream) -> Result<()> {
+        while let Some(chunk) = body.chunk().await? {
+            stream.push(chunk).await?;
+        }
+        Ok(())
+    }
+
+    async fn record_fetch(&self, not_modified: bool) {
+        self.crawler.store().record(StateEvent::PageFetched {
+            base_url: self.base_url.clone(),
+            url: self.url.clone(),
+            history: self.history.clone(),
+            not_modified,
+        });
+        self.crawler.count_fetch(&self.base_url, not_modified).await;
+    }
+
+    async fn record_run_page(&self, page: RunPage) {
+        self.crawler
+            .record_run_page(&self.base_url, &self.url, page)
+            .await;
+    }
+
+    fn parser_worker_handle_link(
+        document_base_url: &Url,
+        link_url: &str,
+        encoding: &'static Encoding,
+    ) -> Result<Url, CrawlerError> {
+        // queries are percent-encoded in the document's own encoding, as browsers do
+        let encoding = encoding.output_encoding();
+        let encode_query: &dyn Fn(&str) -> Cow<'_, [u8]> = &|query| encoding.encode(query).0;
+        let url_options =
+            Url::options().encoding_override((encoding != UTF_8).then_some(encode_query));
+
+        let link_url = match url_options.parse(link_url) {
             Ok(url) => url,
             Err(
                 ParseError::RelativeUrlWithoutBase
@@ -91,7 +285,8 @@ impl UrlWorker {
                 | ParseError::EmptyHost
                 | ParseError::SetHostOnCannotBeABaseUrl,
             ) => {
-                if let Ok(resolved_url) = previous_url.join(link_url) {
+                let url_options = url_options.base_url(Some(document_base_url));
+                if let Ok(resolved_url) = url_options.parse(link_url) {
                     resolved_url
                 } else {
                     return Err(CrawlerError::FailedToResolveRelativeUrl(
@@ -103,18 +298,285 @@ impl UrlWorker {
                 return Err(CrawlerError::CannotParseLinkUrl(err));
             }
         };
-        if !have_same_base(previous_url, &link_url) {
-            let err = CrawlerError::LinkUrlDoesNotMatchBaseUrl(
-                previous_url.to_string(),
-                link_url.to_string(),
-            )
-            .into();
-            return Err(err);
-        }
         Ok(link_url)
     }
 }
 
-fn have_same_base(url1: &Url, url2: &Url) -> bool {
-    url1.scheme() == url2.scheme() && url1.host_str() == url2.host_str()
+// Everything needed to act on the links of one page, which is sent to its parser thread
+struct LinkCollector {
+    crawler: Crawler,
+    base_url: Url,
+    page_url: Url,
+    // where the page was served from, after any redirects
+    document_url: Url,
+    options: CrawlOptions,
+    header_robots: RobotsDirectives,
+}
+
+// What a page's parser found besides its links, sent back once the whole page was parsed
+#[derive(Default)]
+struct PageSummary {
+    analysis: PageAnalysis,
+    // every link that resolved, followed or not
+    outlinks: Vec<Url>,
+}
+
+impl LinkCollector {
+    fn into_consumer(
+        self,
+        header_encoding: Option<&'static Encoding>,
+        summary_sender: oneshot::Sender<PageSummary>,
+    ) -> PageParser {
+        let parser = LinkParser::new(&self.options.links.extract, header_encoding);
+        PageParser {
+            collector: self,
+            parser,
+            outlinks: Vec::new(),
+            summary_sender,
+        }
+    }
+
+    // Acts on the link, returning the URL it resolved to
+    fn handle_link(&self, link: Link, parser: &LinkParser) -> Option<Url> {
+        let page_nofollow = self.header_robots.nofollow
+            || (self.options.robots.meta_nofollow && parser.robots().nofollow);
+        let link_nofollow = self.options.robots.rel_nofollow && link.is_nofollow();
+        let nofollow = page_nofollow || link_nofollow;
+
+        // relative links resolve against the document's <base>, if it has one
+        let document_base_url = document_base_url(&self.document_url, parser.base_href());
+        let link_url = UrlWorker::parser_worker_handle_link(
+            &document_base_url,
+            link.href.as_str(),
+            parser.encoding(),
+        );
+        match link_url {
+            Ok(link_url) => {
+                let base_url = self.base_url.clone();
+                let page_url = self.page_url.clone();
+                let crawler = self.crawler.clone();
+                let follow = link.kind.is_navigational() && !nofollow;
+                if follow {
+                    // queued right away, so the link survives a restart
+                    crawler.spawn_job(base_url.clone(), Some(page_url.clone()), link_url.clone());
+                }
+                let resolved_url = link_url.clone();
+                tokio::spawn(async move {
+                    // every link is kept on its edge, whether or not it gets followed
+                    let fragment = link_url.fragment().map(|fragment| {
+                        percent_decode_str(fragment)
+                            .decode_utf8_lossy()
+                            .into_owned()
+                    });
+                    let edge = link.edge(fragment);
+                    crawler.record_link(&page_url, &link_url, edge).await;
+
+                    if !link.kind.is_navigational() {
+                        let result = crawler
+                            .record_asset(&base_url, &page_url, &link_url, link.kind)
+                            .await;
+                        if let Err(err) = result {
+                            print_error_and_backtrace(err);
+                        }
+                    }
+                });
+                Some(resolved_url)
+            }
+            Err(err) => {
+                err.print();
+                None
+            }
+        }
+    }
+}
+
+// Tokenizes one page as it arrives, on the parser thread it was created on
+struct PageParser {
+    collector: LinkCollector,
+    parser: LinkParser,
+    outlinks: Vec<Url>,
+    summary_sender: oneshot::Sender<PageSummary>,
+}
+
+impl PageParser {
+    fn handle_ready_links(&mut self) {
+        while let Some(link) = self.parser.next_link() {
+            let link_url = self.collector.handle_link(link, &self.parser);
+            self.outlinks.extend(link_url);
+        }
+    }
+}
+
+impl ChunkConsumer for PageParser {
+    fn push(&mut self, chunk: &[u8]) {
+        self.parser.push(chunk);
+        self.handle_ready_links();
+    }
+
+    fn finish(mut self: Box<Self>) {
+        self.parser.finish();
+        self.handle_ready_links();
+        // nobody is waiting if the page's worker gave up on the parser threads
+        let mut analysis = self.parser.take_analysis();
+        // the canonical URL is kept absolute, as it would be followed
+        let document_base_url =
+            document_base_url(&self.collector.document_url, self.parser.base_href());
+        analysis.metadata.canonical = analysis
+            .metadata
+            .canonical
+            .and_then(|href| document_base_url.join(href.trim()).ok())
+            .map(String::from);
+        let _ = self.summary_sender.send(PageSummary {
+            analysis,
+            outlinks: self.outlinks,
+        });
+
+        // the whole document has been parsed, so all meta robots tags are known
+        let collector = self.collector;
+        let robots = self.parser.robots();
+        let meta_noindex = collector.options.robots.meta_noindex && robots.noindex;
+        let meta_nofollow = collector.options.robots.meta_nofollow && robots.nofollow;
+        if meta_noindex || meta_nofollow {
+            tokio::spawn(async move {
+                collector
+                    .crawler
+                    .update_node(&collector.page_url, |node| {
+                        node.noindex |= meta_noindex;
+                        node.nofollow |= meta_nofollow;
+                    })
+                    .await;
+            });
+        }
+    }
+}
+
+// The URL the page ended up being served from, which its relative links resolve against
+fn document_url(url: &Url, redirects: &[Url]) -> Url {
+    redirects.last().unwrap_or(url).clone()
+}
+
+fn document_base_url(document_url: &Url, base_href: Option<&str>) -> Url {
+    base_href
+        .and_then(|base_href| document_url.join(base_href).ok())
+        .unwrap_or_else(|| document_url.clone())
+}
+
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use encoding_rs::{SHIFT_JIS, UTF_16LE, WINDOWS_1252};
+
+    fn resolve(base_href: Option<&str>, link_url: &str) -> String {
+        let document_url = Url::parse("https://example.com/blog/post").unwrap();
+        let document_base_url = document_base_url(&document_url, base_href);
+        UrlWorker::parser_worker_handle_link(&document_base_url, link_url, UTF_8)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_resolve_after_redirect() {
+        let url = Url::parse("https://example.com/old/post").unwrap();
+        let redirects = [
+            Url::parse("https://example.com/moved").unwrap(),
+            Url::parse("https://example.com/blog/post").unwrap(),
+        ];
+        let final_url = document_url(&url, &redirects);
+        let resolved = |base_href: Option<&str>, link_url: &str| {
+            let document_base_url = document_base_url(&final_url, base_href);
+            UrlWorker::parser_worker_handle_link(&document_base_url, link_url, UTF_8)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(resolved(None, "other"), "https://example.com/blog/other");
+        assert_eq!(
+            resolved(Some("/docs/"), "other"),
+            "https://example.com/docs/other"
+        );
+        assert_eq!(document_url(&url, &[]), url);
+    }
+
+    #[test]
+    fn test_resolve_without_base() {
+        assert_eq!(resolve(None, "other"), "https://example.com/blog/other");
+        assert_eq!(resolve(None, "/root"), "https://example.com/root");
+        assert_eq!(
+            resolve(None, "//cdn.example.com/a"),
+            "https://cdn.example.com/a"
+        );
+    }
+
+    #[test]
+    fn test_resolve_relative_path_under_base() {
+        let base_href = Some("https://example.com/docs/v2/");
+        assert_eq!(
+            resolve(base_href, "intro"),
+            "https://example.com/docs/v2/intro"
+        );
+        assert_eq!(
+            resolve(base_href, "../v1/intro"),
+            "https://example.com/docs/v1/intro"
+        );
+    }
+
+    #[test]
+    fn test_resolve_root_relative_under_base() {
+        let base_href = Some("https://other.example.com/docs/");
+        assert_eq!(
+            resolve(base_href, "/about"),
+            "https://other.example.com/about"
+        );
+    }
+
+    #[test]
+    fn test_resolve_protocol_relative_under_base() {
+        let base_href = Some("http://example.com/docs/");
+        assert_eq!(
+            resolve(base_href, "//cdn.example.com/a.js"),
+            "http://cdn.example.com/a.js"
+        );
+    }
+
+    #[test]
+    fn test_relative_base_href() {
+        // the base href itself resolves against the document url
+        assert_eq!(
+            resolve(Some("/static/"), "img.png"),
+            "https://example.com/static/img.png"
+        );
+        // invalid base hrefs are ignored
+        assert_eq!(
+            resolve(Some("http://[::1"), "other"),
+            "https://example.com/blog/other"
+        );
+    }
+
+    #[test]
+    fn test_query_uses_document_encoding() {
+        let document_url = Url::parse("https://example.com/").unwrap();
+        let resolve = |link_url, encoding| {
+            UrlWorker::parser_worker_handle_link(&document_url, link_url, encoding)
+                .unwrap()
+                .to_string()
+        };
+        // paths are always UTF-8, queries follow the document
+        assert_eq!(
+            resolve("/café?q=café", WINDOWS_1252),
+            "https://example.com/caf%C3%A9?q=caf%E9"
+        );
+        assert_eq!(
+            resolve("https://example.com/?q=日本", SHIFT_JIS),
+            // the second byte of `本` is `{`, which queries leave as it is
+            "https://example.com/?q=%93%FA%96{"
+        );
+        assert_eq!(
+            resolve("/café?q=café", UTF_8),
+            "https://example.com/caf%C3%A9?q=caf%C3%A9"
+        );
+        // UTF-16 documents use UTF-8 for their urls
+        assert_eq!(resolve("?q=é", UTF_16LE), "https://example.com/?q=%C3%A9");
+    }
 }
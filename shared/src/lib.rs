@@ -2,7 +2,319 @@ use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Command {
-    Start(String), // Start crawling the provided URL
-    Stop(String),  // Stop crawling the provided URL
-    List,          // List all the crawled URLs
+    Start(String, CrawlOptions), // Start crawling the provided URL
+    Stop(String),                // Stop crawling the provided URL
+    List,                        // List all the crawled URLs
+    // Compare two runs of the crawl of the provided URL, by default the last two
+    Diff(String, Option<u32>, Option<u32>),
+    Broken(String), // List the broken links found by the latest run of the provided URL's crawl
+    Show(String),   // Show what was found about the provided URL when it was last fetched
+    // Check the pages of the latest run of the provided URL's crawl against SEO rules
+    Audit(String, AuditOptions),
+}
+
+/// The service's reply to a `Command`
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    Started(String), // Crawling started from the resolved start URL
+    Stopped(String), // Crawling stopped for the given base URL
+    Ok,              // The command was handled
+    Report(String),  // A report to show the user
+    BrokenLinks(Vec<BrokenLink>),
+    AuditFindings(Vec<AuditFinding>),
+    Error(String), // The command failed
+}
+
+/// A URL that answered with an error status, or couldn't be fetched at all
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BrokenLink {
+    pub url: String,
+    /// e.g. `404 Not Found`, `timed out` or `failed: ...`
+    pub status: String,
+    /// Every crawled page linking to the URL
+    pub referrers: Vec<Referrer>,
+}
+
+/// A page linking to a broken URL, and the text of the link
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Referrer {
+    pub page_url: String,
+    pub text: Option<String>,
+}
+
+/// A problem an audit found with a URL
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditFinding {
+    pub rule: AuditRule,
+    pub url: String,
+    /// What exactly is wrong, e.g. the title shared with other pages
+    pub detail: String,
+}
+
+/// The checks an audit makes, each of which can be skipped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditRule {
+    MissingTitle,
+    DuplicateTitle,
+    MissingDescription,
+    DuplicateDescription,
+    MissingH1,
+    MultipleH1,
+    NonSelfCanonical,    // The canonical URL is another page
+    RedirectChain,       // More redirects in a row than `AuditOptions::max_redirects`
+    OrphanedSitemapUrl,  // Listed in a sitemap, but not linked from any crawled page
+    TooDeep,             // More clicks from the start URL than `AuditOptions::max_depth`
+    NoindexInNavigation, // A noindex page linked from a `<nav>`
+    ImageWithoutAlt,
+}
+
+impl AuditRule {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuditRule::MissingTitle => "missing-title",
+            AuditRule::DuplicateTitle => "duplicate-title",
+            AuditRule::MissingDescription => "missing-description",
+            AuditRule::DuplicateDescription => "duplicate-description",
+            AuditRule::MissingH1 => "missing-h1",
+            AuditRule::MultipleH1 => "multiple-h1",
+            AuditRule::NonSelfCanonical => "non-self-canonical",
+            AuditRule::RedirectChain => "redirect-chain",
+            AuditRule::OrphanedSitemapUrl => "orphaned-sitemap-url",
+            AuditRule::TooDeep => "too-deep",
+            AuditRule::NoindexInNavigation => "noindex-in-navigation",
+            AuditRule::ImageWithoutAlt => "image-without-alt",
+        }
+    }
+}
+
+/// Settings sent along with `Command::Audit`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditOptions {
+    // Rules that aren't checked
+    pub skip: Vec<AuditRule>,
+    // Chains of more redirects than this are reported
+    pub max_redirects: u32,
+    // Pages more clicks than this away from the start URL are reported
+    pub max_depth: u32,
+}
+
+impl Default for AuditOptions {
+    fn default() -> Self {
+        Self {
+            skip: Vec::new(),
+            max_redirects: 1,
+            max_depth: 3,
+        }
+    }
+}
+
+/// Per-crawl settings sent along with `Command::Start`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CrawlOptions {
+    pub scope: ScopePolicy,
+    // Confine the crawl to the directory of the start URL
+    pub path_scope: bool,
+    pub filters: UrlFilters,
+    pub fetch: FetchOptions,
+    pub links: LinkOptions,
+    pub robots: RobotsOptions,
+    pub recrawl: RecrawlPolicy,
+    pub external: ExternalLinkOptions,
+}
+
+/// Decides which links are considered part of the crawled site
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScopePolicy {
+    pub hosts: HostScope,
+    // Treat `http` and `https` links as the same site
+    pub any_scheme: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum HostScope {
+    #[default]
+    ExactHost, // Only the host of the start URL
+    Subdomains,                // The host of the start URL and any of its subdomains
+    RegistrableDomain,         // Any host sharing the start URL's registrable domain
+    AllowedHosts(Vec<String>), // The host of the start URL plus an explicit list of hosts
+}
+
+/// Limits on what gets downloaded for each crawled URL
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FetchOptions {
+    // HTML bodies are truncated after this many bytes
+    pub max_body_bytes: u64,
+    // Send a HEAD request before fetching links that look like downloads (`.pdf`, `.zip`, ...)
+    pub head_suspicious: bool,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 10 * 1024 * 1024,
+            head_suspicious: false,
+        }
+    }
+}
+
+/// Which of the site's own indexing directives the crawler honours
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RobotsOptions {
+    // Don't follow links with `rel="nofollow"`
+    pub rel_nofollow: bool,
+    // Don't follow any links on pages with `<meta name="robots" content="nofollow">`
+    pub meta_nofollow: bool,
+    // Mark pages with `<meta name="robots" content="noindex">` as noindex
+    pub meta_noindex: bool,
+    // Apply `noindex`/`nofollow` from the `X-Robots-Tag` response header
+    pub x_robots_tag: bool,
+}
+
+impl Default for RobotsOptions {
+    fn default() -> Self {
+        Self {
+            rel_nofollow: true,
+            meta_nofollow: true,
+            meta_noindex: true,
+            x_robots_tag: true,
+        }
+    }
+}
+
+/// When pages that were already fetched are fetched again. Whatever the policy, starting a
+/// crawl again fetches every page once more.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub enum RecrawlPolicy {
+    #[default]
+    Never, // Each page is fetched once per start
+    Interval(u64), // Every this many seconds
+    Cron(String),  // Whenever a cron expression (`minute hour day month weekday`) matches
+    // Starts at the minimum interval, halved each time the page changed and doubled each time
+    // it didn't
+    Adaptive {
+        min_secs: u64,
+        max_secs: u64,
+    },
+}
+
+/// Whether links leaving the crawled site are checked. They're never followed either way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExternalLinkOptions {
+    // Check each external link with a HEAD request, or a GET if HEAD isn't allowed
+    pub check: bool,
+    // How long a check may take before the link counts as broken
+    pub timeout_secs: u64,
+    // The wait between two checks of links on the same host
+    pub host_delay_secs: u64,
+}
+
+impl Default for ExternalLinkOptions {
+    fn default() -> Self {
+        Self {
+            check: false,
+            timeout_secs: 10,
+            host_delay_secs: 1,
+        }
+    }
+}
+
+/// Which link-bearing elements are extracted from each page
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkOptions {
+    // Navigational kinds are followed, the rest are recorded as leaves of the page
+    pub extract: Vec<LinkKind>,
+}
+
+impl Default for LinkOptions {
+    fn default() -> Self {
+        Self {
+            extract: vec![LinkKind::AnchorHref],
+        }
+    }
+}
+
+/// The element and attribute a link was found in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LinkKind {
+    AnchorHref,   // <a href>
+    AreaHref,     // <area href>
+    LinkHref,     // <link href>
+    IframeSrc,    // <iframe src>
+    FrameSrc,     // <frame src>
+    ImgSrc,       // <img src>
+    ImgSrcset,    // <img srcset>
+    ScriptSrc,    // <script src>
+    SourceSrc,    // <source src>
+    SourceSrcset, // <source srcset>
+    FormAction,   // <form action>
+    MetaRefresh,  // <meta http-equiv=refresh content="0; url=...">
+    ObjectData,   // <object data>
+}
+
+impl LinkKind {
+    // Whether the link leads to another page, rather than a resource used by this one
+    pub fn is_navigational(&self) -> bool {
+        matches!(
+            self,
+            LinkKind::AnchorHref
+                | LinkKind::AreaHref
+                | LinkKind::IframeSrc
+                | LinkKind::FrameSrc
+                | LinkKind::MetaRefresh
+        )
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LinkKind::AnchorHref => "a href",
+            LinkKind::AreaHref => "area href",
+            LinkKind::LinkHref => "link href",
+            LinkKind::IframeSrc => "iframe src",
+            LinkKind::FrameSrc => "frame src",
+            LinkKind::ImgSrc => "img src",
+            LinkKind::ImgSrcset => "img srcset",
+            LinkKind::ScriptSrc => "script src",
+            LinkKind::SourceSrc => "source src",
+            LinkKind::SourceSrcset => "source srcset",
+            LinkKind::FormAction => "form action",
+            LinkKind::MetaRefresh => "meta refresh",
+            LinkKind::ObjectData => "object data",
+        }
+    }
+}
+
+/// How a page links to another URL: the element it was found in and what it said
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkEdge {
+    pub kind: LinkKind,
+    /// The visible text of an `<a>` (including the `alt` of images inside it), or the `alt`
+    /// of an `<area>`; `None` for elements without text
+    pub text: Option<String>,
+    pub title: Option<String>,
+    pub rel: Option<String>,
+    pub hreflang: Option<String>,
+    pub target: Option<String>,
+    /// The link's `#fragment`, percent-decoded, which should name an element of the page
+    /// linked to
+    pub fragment: Option<String>,
+    /// Whether the element is inside a `<nav>`, i.e. part of the site's navigation
+    pub in_nav: bool,
+    /// The line of the source document the element starts on
+    pub line: u64,
+}
+
+/// Include/exclude rules matched against each link as it was written, without its fragment
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UrlFilters {
+    // If not empty, only links matching one of these are crawled
+    pub include: Vec<UrlPattern>,
+    // Links matching any of these are never crawled
+    pub exclude: Vec<UrlPattern>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum UrlPattern {
+    Glob(String),  // Matched against the path if it starts with `/`, otherwise the whole URL
+    Regex(String), // Searched for anywhere in the whole URL
 }